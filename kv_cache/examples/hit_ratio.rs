@@ -0,0 +1,104 @@
+//! Compares the hit ratios of the eviction policies on a trace of keys.
+//!
+//! ```text
+//! cargo run --release -p kv_cache --example hit_ratio -- [trace file] [budget in bytes]
+//! ```
+//!
+//! A trace file has one key per line, e.g. the keys of the `get` commands logged by a proxy.
+//! Without one a synthetic trace is used: zipf distributed lookups of popular keys, interrupted
+//! by scans over keys which are read only once.
+use kv_cache::{Cache, EvictionPolicy, Lfu, Lru, WTinyLfu};
+use std::fs;
+use std::time::Instant;
+
+const VALUE_SIZE: usize = 100;
+const DEFAULT_BUDGET: usize = 512 * 1024;
+
+const HOT_KEYS: usize = 20_000;
+const ZIPF_EXPONENT: f64 = 0.9;
+const LOOKUPS: usize = 1_000_000;
+const SCAN_EVERY: usize = 100_000;
+const SCAN_LEN: usize = 20_000;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let trace = match args.first() {
+        Some(path) => fs::read_to_string(path)
+            .expect("can not read the trace file")
+            .lines()
+            .map(String::from)
+            .collect(),
+        None => synthetic_trace(),
+    };
+    let budget = args
+        .get(1)
+        .map(|b| b.parse().expect("the budget must be a number of bytes"))
+        .unwrap_or(DEFAULT_BUDGET);
+
+    println!("{} lookups, budget {} bytes", trace.len(), budget);
+    run("lru", &trace, budget, Lru::new());
+    run("lfu", &trace, budget, Lfu::new());
+    run("w-tinylfu", &trace, budget, WTinyLfu::new());
+}
+
+/// Replays the trace, storing every key that is missed like a look-aside cache would.
+fn run(name: &str, trace: &[String], budget: usize, policy: impl EvictionPolicy<String> + 'static) {
+    // nothing expires, so there is nothing for a vacuum thread to do
+    let cache = Cache::<String, Vec<u8>>::builder()
+        .eviction_policy(budget, policy)
+        .without_vacuum_thread()
+        .build();
+    let start = Instant::now();
+    for key in trace {
+        if cache.get(key).is_none() {
+            cache.insert(key.clone(), vec![0; VALUE_SIZE], 0);
+        }
+    }
+    let stats = cache.stats();
+    println!(
+        "{:<10} hit ratio {:>6.2}% ({} hits, {} misses, {} evictions) in {:?}",
+        name,
+        100.0 * stats.hits as f64 / (stats.hits + stats.misses).max(1) as f64,
+        stats.hits,
+        stats.misses,
+        stats.evictions,
+        start.elapsed()
+    );
+}
+
+fn synthetic_trace() -> Vec<String> {
+    // cumulative zipf weights of the hot keys, sampled by binary search
+    let mut cumulative = Vec::with_capacity(HOT_KEYS);
+    let mut total = 0.0;
+    for rank in 1..=HOT_KEYS {
+        total += 1.0 / (rank as f64).powf(ZIPF_EXPONENT);
+        cumulative.push(total);
+    }
+
+    let mut random = XorShift(0x2545_f491_4f6c_dd1d);
+    let mut trace = Vec::with_capacity(LOOKUPS + LOOKUPS / SCAN_EVERY * SCAN_LEN);
+    let mut scanned = 0;
+    for i in 0..LOOKUPS {
+        if i % SCAN_EVERY == SCAN_EVERY - 1 {
+            for _ in 0..SCAN_LEN {
+                trace.push(format!("scan:{}", scanned));
+                scanned += 1;
+            }
+        }
+        let sample = random.next_f64() * total;
+        let rank = cumulative.partition_point(|w| *w < sample);
+        trace.push(format!("hot:{}", rank));
+    }
+    trace
+}
+
+struct XorShift(u64);
+
+impl XorShift {
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
@@ -0,0 +1,133 @@
+use crate::eviction::Eviction;
+use crate::listener::Listener;
+use crate::{ByteSize, Cache, Clock, EvictionPolicy, Lru, MonotonicClock, RemovalCause};
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often the vacuum thread reclaims expired items by default.
+const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Configures and creates a [`Cache`], see [`Cache::builder`].
+pub struct CacheBuilder<K, V> {
+    pub(crate) default_ttl: Option<Duration>,
+    pub(crate) time_to_idle: Option<Duration>,
+    pub(crate) eviction: Option<Eviction<K>>,
+    /// `None` if expired items are reclaimed by the caller instead of a vacuum thread.
+    pub(crate) sweep_interval: Option<Duration>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) negative_ttl: Option<Duration>,
+    pub(crate) listeners: Vec<Listener<K, V>>,
+    /// Clones the values handed to the listeners, set along with the first listener.
+    pub(crate) copy_value: Option<fn(&V) -> V>,
+    pub(crate) value: PhantomData<V>,
+}
+
+impl<K, V> CacheBuilder<K, V>
+where
+    K: Eq + Hash + Clone + ByteSize + Send + Sync + 'static,
+    V: ByteSize + Send + Sync + 'static,
+{
+    pub fn new() -> Self {
+        CacheBuilder {
+            default_ttl: None,
+            time_to_idle: None,
+            eviction: None,
+            sweep_interval: Some(DEFAULT_SWEEP_INTERVAL),
+            clock: Arc::new(MonotonicClock::new()),
+            negative_ttl: None,
+            listeners: Vec::new(),
+            copy_value: None,
+            value: PhantomData,
+        }
+    }
+
+    /// Ttl of the items inserted without one, they never expire if none is set.
+    pub fn default_ttl(mut self, default_ttl: Duration) -> Self {
+        self.default_ttl = Some(default_ttl);
+        self
+    }
+
+    /// Expires the items not accessed for this long, on top of their ttl. Applies to the items
+    /// stored without an idle timeout of their own, see [`Cache::insert_with_idle`].
+    pub fn time_to_idle(mut self, time_to_idle: Duration) -> Self {
+        self.time_to_idle = Some(time_to_idle);
+        self
+    }
+
+    /// Evicts the least recently used items once the items take more than `max_bytes`, see
+    /// [`crate::CacheStats::bytes`] for how items are accounted.
+    pub fn max_bytes(self, max_bytes: usize) -> Self {
+        self.eviction_policy(max_bytes, Lru::new())
+    }
+
+    /// Evicts the items picked by the policy once the items take more than `max_bytes`.
+    pub fn eviction_policy(
+        mut self,
+        max_bytes: usize,
+        policy: impl EvictionPolicy<K> + 'static,
+    ) -> Self {
+        self.eviction = Some(Eviction::new(max_bytes, policy));
+        self
+    }
+
+    /// How often the vacuum thread reclaims expired items, every second by default.
+    pub fn sweep_interval(mut self, interval: Duration) -> Self {
+        self.sweep_interval = Some(interval);
+        self
+    }
+
+    /// Does not spawn a vacuum thread, expired items are only reclaimed by calls to
+    /// [`Cache::sweep`], e.g. from a tokio interval task. Until then they still take memory, but
+    /// are never returned.
+    pub fn without_vacuum_thread(mut self) -> Self {
+        self.sweep_interval = None;
+        self
+    }
+
+    /// The clock telling when items expire, a [`MonotonicClock`] by default. Give it a
+    /// [`crate::MockClock`] to step time in tests.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// How long [`Cache::get_or_load`] remembers that a loader found no value for a key, so the
+    /// backend is not asked again meanwhile. Not remembered at all by default.
+    pub fn negative_ttl(mut self, negative_ttl: Duration) -> Self {
+        self.negative_ttl = Some(negative_ttl);
+        self
+    }
+
+    /// Calls the listener with every item leaving the cache and why it left, with a copy of its
+    /// value. Listeners run one after the other on a thread of their own, in the order the items
+    /// left, so they do not slow down the cache but may lag behind it. Items leaving while many
+    /// are still waiting for the listeners are not handed to them, see
+    /// [`crate::CacheStats::dropped_notifications`].
+    pub fn removal_listener(
+        mut self,
+        listener: impl Fn(&K, &V, RemovalCause) + Send + 'static,
+    ) -> Self
+    where
+        V: Clone,
+    {
+        self.listeners.push(Box::new(listener));
+        self.copy_value = Some(V::clone);
+        self
+    }
+
+    pub fn build(self) -> Cache<K, V> {
+        Cache::from_builder(self)
+    }
+}
+
+impl<K, V> Default for CacheBuilder<K, V>
+where
+    K: Eq + Hash + Clone + ByteSize + Send + Sync + 'static,
+    V: ByteSize + Send + Sync + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,62 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The source of time for expiry and flushes, see [`crate::CacheBuilder::clock`].
+///
+/// Times are durations since an origin of the clock's own choosing, they must never go back.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Duration;
+}
+
+/// Measures time with [`Instant`], so items do not expire early or late when the system time is
+/// changed, e.g. by NTP. The default clock of a cache.
+pub struct MonotonicClock {
+    origin: Instant,
+}
+
+impl MonotonicClock {
+    pub fn new() -> Self {
+        MonotonicClock {
+            origin: Instant::now(),
+        }
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Duration {
+        self.origin.elapsed()
+    }
+}
+
+/// A clock which only moves when told to, so expiry can be tested without sleeping.
+///
+/// Clones share their time: keep one to advance the clock of the cache it was given to.
+#[derive(Clone, Default)]
+pub struct MockClock {
+    micros: Arc<AtomicU64>,
+}
+
+impl MockClock {
+    /// A clock standing at its origin.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Duration {
+        Duration::from_micros(self.micros.load(Ordering::Relaxed))
+    }
+}
@@ -0,0 +1,378 @@
+//! Read-modify-write of a single key. The closures run under the lock of the key's shard, so no
+//! other write to the key can come in between reading the item and storing the result.
+use crate::{micros, ByteSize, Cache, Item, RemovalCause, Value};
+use dashmap::mapref::entry::Entry;
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::mem;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// A live item as seen by the closures of [`Cache::compute`] and [`Cache::compute_if_present`].
+#[derive(Debug)]
+pub struct CurrentItem<'a, V> {
+    pub value: &'a V,
+    pub flag: u32,
+    /// Compare it with a cas unique fetched earlier to only update an unmodified item.
+    pub cas: u64,
+    /// Remaining ttl in seconds, None if the item never expires.
+    pub ttl: Option<u64>,
+}
+
+/// What to do with a key, as decided by a compute closure.
+#[derive(Clone, Debug)]
+pub enum Compute<V> {
+    /// Leaves the key as it is.
+    Keep,
+    /// Stores a value. An existing item keeps its ttl, a new one gets the default ttl of the cache.
+    Set { value: V, flag: u32 },
+    /// Stores a value with a ttl in seconds, 0 meaning it never expires.
+    SetWithTtl { value: V, flag: u32, ttl: u32 },
+    /// Removes the key.
+    Remove,
+}
+
+impl<'a, V> CurrentItem<'a, V> {
+    fn new(v: &'a Value<V>, now: Duration) -> Self {
+        CurrentItem {
+            value: &v.value,
+            flag: v.flag,
+            cas: v.cas,
+            ttl: v.remaining_ttl(now),
+        }
+    }
+}
+
+impl<K, V> Cache<K, V>
+where
+    K: Eq + Hash + Clone + ByteSize + Send + Sync + 'static,
+    V: ByteSize + Clone + Send + Sync + 'static,
+{
+    /// Gets a key, or stores the value made by `f` with the default ttl and no flag if the key is
+    /// missing (or has expired). Concurrent callers for the same key wait, so `f` runs once.
+    ///
+    /// The closure must not use the cache, the shard of the key is locked while it runs.
+    pub fn get_or_insert_with(&self, key: K, f: impl FnOnce() -> V) -> Item<V> {
+        let entry = self.map.entry(key);
+        let hit = match &entry {
+            Entry::Occupied(o) => self.lookup(o.key(), Some((o.key(), o.get()))),
+            Entry::Vacant(v) => self.lookup(v.key(), None),
+        };
+        let item = match entry {
+            Entry::Occupied(o) if hit => Item::from(o.get()),
+            entry => {
+                let value = Value::new(f(), self.default_ttl, 0, self.next_cas(), self.now());
+                let item = Item::from(&value);
+                self.put_entry(entry, value);
+                item
+            }
+        };
+        self.evict();
+        item
+    }
+
+    /// Decides the new state of a key from its item, `None` if the key is missing (or has
+    /// expired). Returns the item stored afterwards, if any.
+    ///
+    /// The closure must not use the cache, the shard of the key is locked while it runs.
+    pub fn compute<F>(&self, key: K, f: F) -> Option<Item<V>>
+    where
+        F: FnOnce(Option<CurrentItem<'_, V>>) -> Compute<V>,
+    {
+        let now = self.now();
+        let item = match self.map.entry(key) {
+            Entry::Occupied(o) if !self.is_expired(o.get()) => {
+                match f(Some(CurrentItem::new(o.get(), now))) {
+                    Compute::Remove => {
+                        self.forget(o.key());
+                        self.journal_delete(o.key());
+                        let (k, v) = o.remove_entry();
+                        self.counters.remove_item(self.item_size(&k, &v));
+                        self.notify(&k, &v.value, RemovalCause::Explicit);
+                        None
+                    }
+                    op => {
+                        let mut r = o.into_ref();
+                        let (k, v) = r.pair_mut();
+                        self.modify(k, v, op, now, |cache, k, v, _| cache.journal_set(k, v));
+                        Some(Item::from(&*v))
+                    }
+                }
+            }
+            entry => {
+                let value = match f(None) {
+                    Compute::Keep | Compute::Remove => None,
+                    Compute::Set { value, flag } => Some(Value::new(
+                        value,
+                        self.default_ttl,
+                        flag,
+                        self.next_cas(),
+                        now,
+                    )),
+                    Compute::SetWithTtl { value, flag, ttl } => {
+                        Some(Value::new_with_ttl(value, ttl, flag, self.next_cas(), now))
+                    }
+                };
+                value.map(|value| {
+                    let item = Item::from(&value);
+                    self.put_entry(entry, value);
+                    item
+                })
+            }
+        };
+        self.evict();
+        item
+    }
+
+    /// Like [`Cache::compute`], but only calls the closure if the key exists (and has not
+    /// expired), so it takes any borrowed form of the key. Returns the item stored afterwards, if
+    /// any.
+    pub fn compute_if_present<Q, F>(&self, key: &Q, f: F) -> Option<Item<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        F: FnOnce(CurrentItem<'_, V>) -> Compute<V>,
+    {
+        self.update_if_present(key, f, |cache, k, v, _| cache.journal_set(k, v))
+    }
+
+    /// [`Cache::compute_if_present`] recording a modified item in the journal with `journal`
+    /// instead of as a whole new value. `journal` also gets the cas unique the item had before.
+    pub(crate) fn update_if_present<Q, F, J>(&self, key: &Q, f: F, journal: J) -> Option<Item<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        F: FnOnce(CurrentItem<'_, V>) -> Compute<V>,
+        J: FnOnce(&Self, &K, &Value<V>, u64),
+    {
+        let now = self.now();
+        let mut item = None;
+        let removed = self.map.remove_if_mut(key, |k, v| {
+            if self.is_expired(v) {
+                return false;
+            }
+            match f(CurrentItem::new(v, now)) {
+                Compute::Remove => {
+                    self.forget(k);
+                    self.journal_delete(k);
+                    true
+                }
+                op => {
+                    self.modify(k, v, op, now, journal);
+                    item = Some(Item::from(&*v));
+                    false
+                }
+            }
+        });
+        if let Some((k, v)) = removed {
+            self.counters.remove_item(self.item_size(&k, &v));
+            self.notify(&k, &v.value, RemovalCause::Explicit);
+        }
+        self.evict();
+        item
+    }
+
+    /// Stores the value of a compute into a live item in place. It stays the same item: it keeps
+    /// its ttl unless given a new one, and like an append it is not counted as expired unfetched.
+    fn modify(
+        &self,
+        key: &K,
+        v: &mut Value<V>,
+        op: Compute<V>,
+        now: Duration,
+        journal: impl FnOnce(&Self, &K, &Value<V>, u64),
+    ) {
+        let (value, flag) = match op {
+            // removing is up to the caller, which holds the entry
+            Compute::Keep | Compute::Remove => return,
+            Compute::Set { value, flag } => (value, flag),
+            Compute::SetWithTtl { value, flag, ttl } => {
+                v.set_ttl(ttl, now);
+                self.schedule_expiry(key, v);
+                (value, flag)
+            }
+        };
+        self.counters
+            .add_bytes(value.byte_size() as i64 - v.value.byte_size() as i64);
+        self.counters.record_store(true);
+        let old = mem::replace(&mut v.value, value);
+        self.notify(key, &old, RemovalCause::Replaced);
+        v.flag = flag;
+        let previous_cas = mem::replace(&mut v.cas, self.next_cas());
+        v.updated = now;
+        v.fetched.store(true, Ordering::Relaxed);
+        v.accessed.store(micros(now), Ordering::Relaxed);
+        journal(self, key, v, previous_cas);
+        self.record_use(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockClock;
+
+    fn cache(clock: &MockClock) -> Cache<String, String> {
+        Cache::builder()
+            .clock(clock.clone())
+            .default_ttl(Duration::from_secs(100))
+            .without_vacuum_thread()
+            .build()
+    }
+
+    fn get(cache: &Cache<String, String>, key: &str) -> Option<String> {
+        cache.get(key).map(Item::into_value)
+    }
+
+    #[test]
+    fn keeps_an_item() {
+        let cache = cache(&MockClock::new());
+        cache.insert_with_ttl("a".into(), "1".into(), 10, 3);
+        let cas = cache.get("a").unwrap().get_cas();
+
+        let item = cache
+            .compute("a".into(), |current| {
+                let current = current.unwrap();
+                assert_eq!((current.value.as_str(), current.flag), ("1", 3));
+                assert_eq!((current.cas, current.ttl), (cas, Some(10)));
+                Compute::Keep
+            })
+            .unwrap();
+        assert_eq!(item.get_cas(), cas);
+        assert_eq!(get(&cache, "a").as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn sets_an_item() {
+        let cache = cache(&MockClock::new());
+        cache.insert_with_ttl("a".into(), "1".into(), 10, 0);
+        let cas = cache.get("a").unwrap().get_cas();
+
+        let item = cache
+            .compute("a".into(), |current| Compute::Set {
+                value: format!("{}2", current.unwrap().value),
+                flag: 5,
+            })
+            .unwrap();
+        assert_ne!(item.get_cas(), cas);
+        assert_eq!(item.get_flag(), 5);
+        assert_eq!(item.into_value(), "12");
+        // the item keeps its ttl
+        cache.compute("a".into(), |current| {
+            assert_eq!(current.unwrap().ttl, Some(10));
+            Compute::Keep
+        });
+
+        cache.compute("a".into(), |_| Compute::SetWithTtl {
+            value: "3".into(),
+            flag: 0,
+            ttl: 20,
+        });
+        cache.compute("a".into(), |current| {
+            assert_eq!(current.unwrap().ttl, Some(20));
+            Compute::Keep
+        });
+    }
+
+    #[test]
+    fn removes_an_item() {
+        let cache = cache(&MockClock::new());
+        cache.insert("a".into(), "1".into(), 0);
+
+        assert!(cache.compute("a".into(), |_| Compute::Remove).is_none());
+        assert_eq!(get(&cache, "a"), None);
+        assert!(cache.is_empty());
+        assert_eq!(cache.stats().bytes, 0);
+    }
+
+    #[test]
+    fn computes_a_missing_key() {
+        let cache = cache(&MockClock::new());
+        assert!(cache
+            .compute("a".into(), |current| {
+                assert!(current.is_none());
+                Compute::Keep
+            })
+            .is_none());
+        assert!(cache.compute("a".into(), |_| Compute::Remove).is_none());
+        assert!(cache.is_empty());
+
+        let item = cache.compute("a".into(), |_| Compute::Set {
+            value: "1".into(),
+            flag: 2,
+        });
+        assert_eq!(item.unwrap().get_flag(), 2);
+        // a new item gets the default ttl
+        cache.compute("a".into(), |current| {
+            assert_eq!(current.unwrap().ttl, Some(100));
+            Compute::Keep
+        });
+
+        let mut called = false;
+        assert!(cache
+            .compute_if_present("b", |_| {
+                called = true;
+                Compute::Remove
+            })
+            .is_none());
+        assert!(!called);
+    }
+
+    #[test]
+    fn takes_an_expired_key_as_missing() {
+        let clock = MockClock::new();
+        let cache = cache(&clock);
+        cache.insert_with_ttl("a".into(), "1".into(), 1, 0);
+        clock.advance(Duration::from_secs(2));
+
+        let mut called = false;
+        cache.compute_if_present("a", |_| {
+            called = true;
+            Compute::Keep
+        });
+        assert!(!called);
+        let item = cache.compute("a".into(), |current| {
+            assert!(current.is_none());
+            Compute::SetWithTtl {
+                value: "2".into(),
+                flag: 0,
+                ttl: 0,
+            }
+        });
+        assert_eq!(item.unwrap().into_value(), "2");
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn inserts_a_missing_or_expired_key_once() {
+        let clock = MockClock::new();
+        let cache = cache(&clock);
+        let mut runs = 0;
+        let mut make = |value: &str| {
+            runs += 1;
+            value.to_string()
+        };
+
+        assert_eq!(
+            cache
+                .get_or_insert_with("a".into(), || make("1"))
+                .into_value(),
+            "1"
+        );
+        assert_eq!(
+            cache
+                .get_or_insert_with("a".into(), || make("2"))
+                .into_value(),
+            "1"
+        );
+        clock.advance(Duration::from_secs(101));
+        assert_eq!(
+            cache
+                .get_or_insert_with("a".into(), || make("3"))
+                .into_value(),
+            "3"
+        );
+        assert_eq!(runs, 2);
+        assert_eq!(cache.len(), 1);
+    }
+}
@@ -0,0 +1,91 @@
+use super::EvictionPolicy;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Evicts the least frequently used key first, the least recently used one among keys used
+/// equally often.
+///
+/// Use counts are never decayed, so keys that were popular once stay cached for long.
+pub struct Lfu<K> {
+    tick: u64,
+    /// Use count and last use tick of every key.
+    uses: HashMap<K, (u64, u64)>,
+    order: BTreeMap<(u64, u64), K>,
+}
+
+impl<K: Eq + Hash + Clone> Lfu<K> {
+    pub fn new() -> Self {
+        Lfu {
+            tick: 0,
+            uses: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone> Default for Lfu<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Eq + Hash + Clone + Send> EvictionPolicy<K> for Lfu<K> {
+    fn record_use(&mut self, key: &K) {
+        self.tick += 1;
+        match self.uses.get_mut(key) {
+            Some(uses) => {
+                let key = self
+                    .order
+                    .remove(uses)
+                    .expect("tracked key has a use count");
+                *uses = (uses.0 + 1, self.tick);
+                self.order.insert(*uses, key);
+            }
+            None => {
+                self.uses.insert(key.clone(), (1, self.tick));
+                self.order.insert((1, self.tick), key.clone());
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(uses) = self.uses.remove(key) {
+            self.order.remove(&uses);
+        }
+    }
+
+    fn contains(&self, key: &K) -> bool {
+        self.uses.contains_key(key)
+    }
+
+    fn evict(&mut self) -> Option<K> {
+        let (_, key) = self.order.pop_first()?;
+        self.uses.remove(&key);
+        Some(key)
+    }
+
+    /// One in the use counts, one in the order.
+    fn key_copies(&self) -> usize {
+        2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ties_break_by_recency() {
+        let mut lfu = Lfu::new();
+        for key in ["a", "b", "a", "b", "c", "c", "c"] {
+            lfu.record_use(&key);
+        }
+        lfu.record_use(&"d");
+        assert_eq!(lfu.evict(), Some("d"));
+        // "a" and "b" were both used twice, "a" less recently
+        assert_eq!(lfu.evict(), Some("a"));
+        assert_eq!(lfu.evict(), Some("b"));
+        assert_eq!(lfu.evict(), Some("c"));
+        assert_eq!(lfu.evict(), None);
+    }
+}
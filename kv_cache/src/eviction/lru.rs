@@ -0,0 +1,122 @@
+use super::EvictionPolicy;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Evicts the least recently used key first.
+///
+/// Every use of a key gets a new tick, the key with the lowest tick is the least recently used.
+pub struct Lru<K> {
+    tick: u64,
+    ticks: HashMap<K, u64>,
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Eq + Hash + Clone> Lru<K> {
+    pub fn new() -> Self {
+        Lru {
+            tick: 0,
+            ticks: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ticks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ticks.is_empty()
+    }
+
+    /// Marks a key as the most recently used one, starts tracking it if it is new.
+    pub fn touch(&mut self, key: &K) {
+        self.tick += 1;
+        match self.ticks.get_mut(key) {
+            Some(tick) => {
+                let key = self.order.remove(tick).expect("tracked key has a tick");
+                *tick = self.tick;
+                self.order.insert(self.tick, key);
+            }
+            None => {
+                self.ticks.insert(key.clone(), self.tick);
+                self.order.insert(self.tick, key.clone());
+            }
+        }
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.ticks.contains_key(key)
+    }
+
+    pub fn remove(&mut self, key: &K) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.order.remove(&tick);
+        }
+    }
+
+    /// The least recently used key.
+    pub fn oldest(&self) -> Option<&K> {
+        self.order.first_key_value().map(|(_, key)| key)
+    }
+
+    /// The most recently used key.
+    pub fn newest(&self) -> Option<&K> {
+        self.order.last_key_value().map(|(_, key)| key)
+    }
+
+    /// Stops tracking the least recently used key and returns it.
+    pub fn pop(&mut self) -> Option<K> {
+        let (_, key) = self.order.pop_first()?;
+        self.ticks.remove(&key);
+        Some(key)
+    }
+}
+
+impl<K: Eq + Hash + Clone> Default for Lru<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Eq + Hash + Clone + Send> EvictionPolicy<K> for Lru<K> {
+    fn record_use(&mut self, key: &K) {
+        self.touch(key);
+    }
+
+    fn remove(&mut self, key: &K) {
+        Lru::remove(self, key)
+    }
+
+    fn contains(&self, key: &K) -> bool {
+        Lru::contains(self, key)
+    }
+
+    fn evict(&mut self) -> Option<K> {
+        self.pop()
+    }
+
+    /// One in the ticks, one in the order.
+    fn key_copies(&self) -> usize {
+        2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut lru = Lru::new();
+        for key in ["a", "b", "c"] {
+            lru.record_use(&key);
+        }
+        lru.record_use(&"a");
+        assert_eq!(lru.oldest(), Some(&"b"));
+        assert_eq!(lru.newest(), Some(&"a"));
+        assert_eq!(lru.evict(), Some("b"));
+        assert_eq!(lru.evict(), Some("c"));
+        assert_eq!(lru.evict(), Some("a"));
+        assert_eq!(lru.evict(), None);
+    }
+}
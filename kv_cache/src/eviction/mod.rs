@@ -0,0 +1,205 @@
+mod lfu;
+mod lru;
+mod tiny_lfu;
+
+pub use lfu::Lfu;
+pub use lru::Lru;
+pub use tiny_lfu::WTinyLfu;
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+/// Decides which items to evict once a cache is over its byte budget, see
+/// [`crate::Cache::with_eviction_policy`].
+///
+/// The cache calls the policy while holding the lock on the shard of the key, so the policy and
+/// the map always agree on which keys exist. Lookups are buffered and handed to the policy in
+/// batches, a few of them may be lost when many threads read at once.
+pub trait EvictionPolicy<K>: Send {
+    /// Records a use of a key stored in the cache. A key the policy does not track yet was just
+    /// inserted.
+    fn record_use(&mut self, key: &K);
+
+    /// Records a lookup which found the key. The lookup was buffered, so the key may have been
+    /// removed since: only keys the policy still tracks are used.
+    fn record_hit(&mut self, key: &K) {
+        if self.contains(key) {
+            self.record_use(key);
+        }
+    }
+
+    /// Records a lookup of a key that is not in the cache. The key may be looked up in a
+    /// borrowed form, so the policy only gets its [`key_hash`].
+    fn record_miss(&mut self, _hash: u64) {}
+
+    /// Stops tracking a key removed from the cache.
+    fn remove(&mut self, key: &K);
+
+    fn contains(&self, key: &K) -> bool;
+
+    /// Picks the next key to evict and stops tracking it, `None` if no key is tracked.
+    fn evict(&mut self) -> Option<K>;
+
+    /// How many copies of each tracked key the policy keeps. They count against the byte budget
+    /// with the item, without them small items would take several times the budget.
+    fn key_copies(&self) -> usize;
+}
+
+/// Hashes a key the same way for all caches of the process, a key and its borrowed forms get the
+/// same hash.
+pub fn key_hash<Q: Hash + ?Sized>(key: &Q) -> u64 {
+    static HASHER: OnceLock<RandomState> = OnceLock::new();
+    HASHER.get_or_init(RandomState::new).hash_one(key)
+}
+
+/// Number of read buffers, each thread records its lookups into one of them.
+const READ_STRIPES: usize = 16;
+/// Lookups a read buffer holds, once it is full they are handed to the policy.
+const READ_STRIPE_LEN: usize = 32;
+
+static NEXT_READ_STRIPE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The read buffer of the thread.
+    static READ_STRIPE: usize = NEXT_READ_STRIPE.fetch_add(1, Ordering::Relaxed) % READ_STRIPES;
+}
+
+/// A lookup waiting in a read buffer.
+enum Read<K> {
+    Hit(K),
+    /// The [`key_hash`] of a missing key.
+    Miss(u64),
+}
+
+/// A byte budget and the policy picking the items to evict to stay within it.
+pub(crate) struct Eviction<K> {
+    pub(crate) max_bytes: usize,
+    /// [`EvictionPolicy::key_copies`] of the policy, asked once so accounting needs no lock.
+    pub(crate) key_copies: usize,
+    policy: Mutex<Box<dyn EvictionPolicy<K>>>,
+    /// Lookups not handed to the policy yet, so reads do not all wait for its lock.
+    reads: Box<[Mutex<Vec<Read<K>>>]>,
+}
+
+impl<K: Clone> Eviction<K> {
+    pub(crate) fn new(max_bytes: usize, policy: impl EvictionPolicy<K> + 'static) -> Self {
+        Eviction {
+            max_bytes,
+            key_copies: policy.key_copies(),
+            policy: Mutex::new(Box::new(policy)),
+            reads: (0..READ_STRIPES)
+                .map(|_| Mutex::new(Vec::with_capacity(READ_STRIPE_LEN)))
+                .collect(),
+        }
+    }
+
+    /// Locks the policy to record a write.
+    pub(crate) fn policy(&self) -> MutexGuard<'_, Box<dyn EvictionPolicy<K>>> {
+        self.policy.lock().unwrap()
+    }
+
+    /// Locks the policy once it has seen all the buffered lookups, to pick the keys to evict.
+    pub(crate) fn drained_policy(&self) -> MutexGuard<'_, Box<dyn EvictionPolicy<K>>> {
+        let mut policy = self.policy();
+        for reads in self.reads.iter() {
+            replay(&mut policy, &mut reads.lock().unwrap());
+        }
+        policy
+    }
+
+    /// Buffers a lookup which found the key.
+    pub(crate) fn record_hit(&self, key: &K) {
+        self.record_read(|| Read::Hit(key.clone()));
+    }
+
+    /// Buffers a lookup of a missing key, given by its [`key_hash`].
+    pub(crate) fn record_miss(&self, hash: u64) {
+        self.record_read(|| Read::Miss(hash));
+    }
+
+    /// Adds a lookup to the buffer of the thread, which is handed to the policy once full. Never
+    /// waits: the lookup is dropped if another thread holds the buffer, or if the buffer is full
+    /// while another thread holds the policy.
+    fn record_read(&self, read: impl FnOnce() -> Read<K>) {
+        let stripe = READ_STRIPE.with(|stripe| *stripe);
+        let mut reads = match self.reads[stripe].try_lock() {
+            Ok(reads) => reads,
+            Err(_) => return,
+        };
+        if reads.len() < READ_STRIPE_LEN {
+            reads.push(read());
+            if reads.len() < READ_STRIPE_LEN {
+                return;
+            }
+        }
+        if let Ok(mut policy) = self.policy.try_lock() {
+            replay(&mut policy, &mut reads);
+        }
+    }
+}
+
+fn replay<K>(policy: &mut Box<dyn EvictionPolicy<K>>, reads: &mut Vec<Read<K>>) {
+    for read in reads.drain(..) {
+        match read {
+            Read::Hit(key) => policy.record_hit(&key),
+            Read::Miss(hash) => policy.record_miss(hash),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tracks ten keys, removes one and evicts the others, checking `contains` after each step.
+    fn check_contains(mut policy: impl EvictionPolicy<u32>) {
+        for key in 0..10 {
+            policy.record_use(&key);
+            assert!(policy.contains(&key));
+        }
+        policy.record_use(&3);
+        policy.remove(&3);
+        assert!(!policy.contains(&3));
+        policy.remove(&3);
+
+        let mut evicted = Vec::new();
+        while let Some(key) = policy.evict() {
+            assert!(!policy.contains(&key));
+            evicted.push(key);
+        }
+        evicted.sort_unstable();
+        assert_eq!(evicted, [0, 1, 2, 4, 5, 6, 7, 8, 9]);
+        assert!((0..10).all(|key| !policy.contains(&key)));
+    }
+
+    #[test]
+    fn reads_do_not_wait_for_the_policy() {
+        let eviction = Eviction::new(0, Lru::new());
+        {
+            let mut policy = eviction.policy();
+            policy.record_use(&1);
+            policy.record_use(&2);
+            policy.record_use(&3);
+            eviction.record_hit(&3);
+            // the buffer fills up while the policy is locked, then drops the lookups
+            for _ in 0..READ_STRIPE_LEN * 2 {
+                eviction.record_hit(&1);
+            }
+            policy.remove(&3);
+        }
+        let mut policy = eviction.drained_policy();
+        assert_eq!(policy.evict(), Some(2));
+        assert_eq!(policy.evict(), Some(1));
+        // the key was removed before its lookup was replayed
+        assert!(!policy.contains(&3));
+    }
+
+    #[test]
+    fn evict_and_remove_keep_contains_consistent() {
+        check_contains(Lru::new());
+        check_contains(Lfu::new());
+        check_contains(WTinyLfu::new());
+    }
+}
@@ -0,0 +1,210 @@
+use super::{key_hash, EvictionPolicy, Lru};
+use std::hash::Hash;
+
+/// Share of the keys kept in the admission window, in percent.
+const WINDOW_PERCENT: usize = 1;
+/// Share of the main space kept for keys used again after their admission, in percent.
+const PROTECTED_PERCENT: usize = 80;
+
+/// Window TinyLFU: a small LRU window in front of a segmented LRU main space, guarded by a
+/// frequency filter.
+///
+/// New keys enter the window and move on to the probation segment of the main space once the
+/// window is full. A key used again while on probation is promoted to the protected segment.
+/// When something has to go, the newest key on probation is only kept if it was used more often
+/// than the oldest one, so a scan of keys used once can not flush out the popular keys.
+///
+/// Use frequencies are estimated by a count-min sketch, which also counts lookups of keys that
+/// are not cached and forgets old uses over time.
+pub struct WTinyLfu<K> {
+    sketch: CountMinSketch,
+    window: Lru<K>,
+    probation: Lru<K>,
+    protected: Lru<K>,
+}
+
+impl<K: Eq + Hash + Clone> WTinyLfu<K> {
+    pub fn new() -> Self {
+        WTinyLfu {
+            sketch: CountMinSketch::new(MIN_SKETCH_WIDTH),
+            window: Lru::new(),
+            probation: Lru::new(),
+            protected: Lru::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.window.len() + self.probation.len() + self.protected.len()
+    }
+
+    fn admit_new(&mut self, key: &K) {
+        self.window.touch(key);
+        if self.window.len() > (self.len() * WINDOW_PERCENT / 100).max(1) {
+            let key = self.window.pop().expect("window is not empty");
+            self.probation.touch(&key);
+        }
+        // a sketch narrower than the number of keys can not tell their frequencies apart, the
+        // old counts are dropped as they do not fit the new width
+        if self.len() > self.sketch.width() {
+            self.sketch = CountMinSketch::new(self.sketch.width() * 2);
+        }
+    }
+
+    fn promote(&mut self, key: &K) {
+        self.probation.remove(key);
+        self.protected.touch(key);
+        let main_len = self.probation.len() + self.protected.len();
+        if self.protected.len() > main_len * PROTECTED_PERCENT / 100 {
+            let key = self.protected.pop().expect("protected is not empty");
+            self.probation.touch(&key);
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone> Default for WTinyLfu<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Eq + Hash + Clone + Send> EvictionPolicy<K> for WTinyLfu<K> {
+    fn record_use(&mut self, key: &K) {
+        self.sketch.increment(key_hash(key));
+        if self.window.contains(key) {
+            self.window.touch(key);
+        } else if self.probation.contains(key) {
+            self.promote(key);
+        } else if self.protected.contains(key) {
+            self.protected.touch(key);
+        } else {
+            self.admit_new(key);
+        }
+    }
+
+    fn record_miss(&mut self, hash: u64) {
+        self.sketch.increment(hash);
+    }
+
+    fn remove(&mut self, key: &K) {
+        self.window.remove(key);
+        self.probation.remove(key);
+        self.protected.remove(key);
+    }
+
+    fn contains(&self, key: &K) -> bool {
+        self.window.contains(key) || self.probation.contains(key) || self.protected.contains(key)
+    }
+
+    fn evict(&mut self) -> Option<K> {
+        let (candidate, victim) = match (self.probation.newest(), self.probation.oldest()) {
+            (Some(candidate), Some(victim)) => (candidate, victim),
+            _ => return self.protected.pop().or_else(|| self.window.pop()),
+        };
+        let key = if self.sketch.frequency(key_hash(candidate))
+            > self.sketch.frequency(key_hash(victim))
+        {
+            victim.clone()
+        } else {
+            candidate.clone()
+        };
+        self.probation.remove(&key);
+        Some(key)
+    }
+
+    /// A key is in one segment at a time, the sketch only keeps its hash.
+    fn key_copies(&self) -> usize {
+        2
+    }
+}
+
+const MIN_SKETCH_WIDTH: usize = 64;
+const SKETCH_DEPTH: usize = 4;
+/// Counters stop at this value, they are halved every `RESET_FACTOR * width` increments so the
+/// sketch favors recent uses.
+const MAX_COUNT: u8 = 15;
+const RESET_FACTOR: usize = 10;
+const SEEDS: [u64; SKETCH_DEPTH] = [
+    0xc3a5_c85c_97cb_3127,
+    0xb492_b66f_be98_f273,
+    0x9ae1_6a3b_2f90_404f,
+    0xcbf2_9ce4_8422_2325,
+];
+
+/// Estimates how often keys were used, in a fixed amount of memory.
+///
+/// Every key increments one counter per row, its frequency is the smallest of those counters.
+/// Keys are given by their [`key_hash`].
+struct CountMinSketch {
+    counters: Vec<u8>,
+    width: usize,
+    increments: usize,
+}
+
+impl CountMinSketch {
+    fn new(width: usize) -> Self {
+        let width = width.next_power_of_two();
+        CountMinSketch {
+            counters: vec![0; width * SKETCH_DEPTH],
+            width,
+            increments: 0,
+        }
+    }
+
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn indexes(&self, hash: u64) -> [usize; SKETCH_DEPTH] {
+        let mut indexes = [0; SKETCH_DEPTH];
+        for (row, seed) in SEEDS.iter().enumerate() {
+            let h = (hash ^ seed).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+            indexes[row] = row * self.width + ((h >> 32) as usize & (self.width - 1));
+        }
+        indexes
+    }
+
+    fn increment(&mut self, hash: u64) {
+        for i in self.indexes(hash) {
+            self.counters[i] = (self.counters[i] + 1).min(MAX_COUNT);
+        }
+        self.increments += 1;
+        if self.increments >= RESET_FACTOR * self.width {
+            for counter in self.counters.iter_mut() {
+                *counter /= 2;
+            }
+            self.increments /= 2;
+        }
+    }
+
+    fn frequency(&self, hash: u64) -> u8 {
+        self.indexes(hash)
+            .iter()
+            .map(|i| self.counters[*i])
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scan_does_not_evict_hot_key() {
+        const CAPACITY: usize = 10;
+        let mut policy = WTinyLfu::new();
+        // the hot key reaches the highest count, which a key used once never beats
+        for _ in 0..MAX_COUNT {
+            policy.record_use(&0u32);
+        }
+        // few enough uses that the sketch is not halved
+        for key in 1..500 {
+            policy.record_use(&key);
+            while policy.len() > CAPACITY {
+                let evicted = policy.evict().expect("policy is not empty");
+                assert_ne!(evicted, 0);
+            }
+        }
+        assert!(policy.contains(&0));
+    }
+}
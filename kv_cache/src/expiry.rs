@@ -0,0 +1,50 @@
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Keys grouped by the second of the cache clock they expire in, so the sweep only visits the keys
+/// that are due instead of walking the whole map.
+///
+/// Keys are not taken out when they are removed or get a new ttl, the index only ever adds. The
+/// cache remembers on each value the earliest bucket it is pending in and only adds a key again
+/// for an earlier bucket, the sweep checks every due key against the map and schedules the ones
+/// still alive again. A key is in the index about once, however often it is stored.
+pub(crate) struct ExpiryIndex<K> {
+    buckets: Mutex<BTreeMap<u64, Vec<K>>>,
+}
+
+impl<K: Clone> ExpiryIndex<K> {
+    pub(crate) fn new() -> Self {
+        ExpiryIndex {
+            buckets: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The bucket a deadline falls in, never 0.
+    pub(crate) fn bucket(deadline: Duration) -> u64 {
+        // rounded up, every key in a bucket has expired once the second of the bucket has come
+        deadline.as_secs() + 1
+    }
+
+    /// Schedules a key to be checked once the second of the bucket has passed.
+    pub(crate) fn insert(&self, key: &K, bucket: u64) {
+        self.buckets
+            .lock()
+            .unwrap()
+            .entry(bucket)
+            .or_default()
+            .push(key.clone());
+    }
+
+    /// Takes out the keys of all buckets which are due at the given time, with their bucket.
+    pub(crate) fn take_due(&self, now: Duration) -> Vec<(u64, K)> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let later = buckets.split_off(&(now.as_secs() + 1));
+        let due = mem::replace(&mut *buckets, later);
+        drop(buckets);
+        due.into_iter()
+            .flat_map(|(bucket, keys)| keys.into_iter().map(move |key| (bucket, key)))
+            .collect()
+    }
+}
@@ -0,0 +1,573 @@
+//! A journal of the changes made to a cache, e.g. to append them to a log which is replayed on top
+//! of a snapshot after a restart.
+//!
+//! A journal starts with [`JOURNAL_HEADER`], then has one record per change: its length and the
+//! CRC-32 of its body, both big endian u32, then the body. Times are milliseconds since the unix
+//! epoch, so a replay knows which items expired meanwhile.
+use crate::snapshot::{crc32, Input, CRC32_INIT};
+use crate::{ByteSize, Cache, SnapshotError, Value};
+use std::hash::Hash;
+use std::io::{self, ErrorKind, Read};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The magic and the format version a journal starts with.
+pub const JOURNAL_HEADER: &[u8; 12] = b"MCKVLOG\0\0\0\0\x01";
+
+const TAG_SET: u8 = 1;
+const TAG_TOUCH: u8 = 2;
+const TAG_DELETE: u8 = 3;
+const TAG_FLUSH: u8 = 4;
+const TAG_APPEND: u8 = 5;
+const TAG_PREPEND: u8 = 6;
+/// Expiry of an item which never expires.
+const NEVER: u64 = u64::MAX;
+
+/// Receives the changes made to a cache, see [`Cache::set_journal`].
+///
+/// Changes are recorded while the key is locked, so the changes of a key come in the order they
+/// were made, and a slow journal slows down the writes to the cache.
+pub trait Journal<K, V>: Send + Sync {
+    fn record(&self, change: Change<'_, K, V>);
+}
+
+/// A change made to a cache. Expired and evicted items are not recorded, a replay expires and
+/// evicts them again.
+#[derive(Debug)]
+pub enum Change<'a, K, V> {
+    /// An item was stored or updated, e.g. by an incr.
+    Set {
+        key: &'a K,
+        value: &'a V,
+        flag: u32,
+        cas: u64,
+        expires_at: Option<SystemTime>,
+        idle: Option<Duration>,
+    },
+    /// The ttl of an item was updated.
+    Touch {
+        key: &'a K,
+        expires_at: Option<SystemTime>,
+    },
+    /// Data was added after the value of an item, which keeps its flag and ttl. Recorded rather
+    /// than the whole new value, so appending to a value does not take time quadratic in its
+    /// size to record.
+    ///
+    /// A replay only adds the data to the item which had `previous_cas`, so replaying it on top of
+    /// a snapshot which has the data already does not add it twice.
+    Append {
+        key: &'a K,
+        data: &'a [u8],
+        previous_cas: u64,
+        cas: u64,
+    },
+    /// Data was added before the value of an item, like [`Change::Append`].
+    Prepend {
+        key: &'a K,
+        data: &'a [u8],
+        previous_cas: u64,
+        cas: u64,
+    },
+    Delete {
+        key: &'a K,
+    },
+    /// The items stored before this time are invalid once it has passed.
+    Flush {
+        at: SystemTime,
+    },
+}
+
+/// What a replay of a journal did, see [`Cache::replay_journal`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Replay {
+    /// Number of changes replayed.
+    pub changes: u64,
+    /// False if the journal ends with a record which was cut short or does not match its
+    /// checksum, e.g. after a crash while it was written. The changes before it are replayed.
+    pub complete: bool,
+}
+
+impl<K: AsRef<[u8]>, V: AsRef<[u8]>> Change<'_, K, V> {
+    /// Appends the record of the change to a journal buffer.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let start = out.len();
+        // length and checksum, known once the body is written
+        out.extend_from_slice(&[0; 8]);
+        match self {
+            Change::Set {
+                key,
+                value,
+                flag,
+                cas,
+                expires_at,
+                idle,
+            } => {
+                out.push(TAG_SET);
+                out.extend_from_slice(&flag.to_be_bytes());
+                out.extend_from_slice(&cas.to_be_bytes());
+                out.extend_from_slice(&encode_time(*expires_at).to_be_bytes());
+                let idle = idle.map_or(0, |idle| idle.as_millis() as u64);
+                out.extend_from_slice(&idle.to_be_bytes());
+                encode_bytes(out, key.as_ref());
+                encode_bytes(out, value.as_ref());
+            }
+            Change::Touch { key, expires_at } => {
+                out.push(TAG_TOUCH);
+                out.extend_from_slice(&encode_time(*expires_at).to_be_bytes());
+                encode_bytes(out, key.as_ref());
+            }
+            Change::Append {
+                key,
+                data,
+                previous_cas,
+                cas,
+            }
+            | Change::Prepend {
+                key,
+                data,
+                previous_cas,
+                cas,
+            } => {
+                let tag = match self {
+                    Change::Append { .. } => TAG_APPEND,
+                    _ => TAG_PREPEND,
+                };
+                out.push(tag);
+                out.extend_from_slice(&previous_cas.to_be_bytes());
+                out.extend_from_slice(&cas.to_be_bytes());
+                encode_bytes(out, key.as_ref());
+                encode_bytes(out, data);
+            }
+            Change::Delete { key } => {
+                out.push(TAG_DELETE);
+                encode_bytes(out, key.as_ref());
+            }
+            Change::Flush { at } => {
+                out.push(TAG_FLUSH);
+                out.extend_from_slice(&encode_time(Some(*at)).to_be_bytes());
+            }
+        }
+        let body = &out[start + 8..];
+        let len = (body.len() as u32).to_be_bytes();
+        let checksum = (crc32(CRC32_INIT, body) ^ CRC32_INIT).to_be_bytes();
+        out[start..start + 4].copy_from_slice(&len);
+        out[start + 4..start + 8].copy_from_slice(&checksum);
+    }
+}
+
+/// Reads `len` bytes into `buf`, fewer if the reader ends first. Only what is there is allocated,
+/// so a length torn by a crash does not allocate more than the rest of the journal.
+fn read_up_to<R: Read>(reader: &mut R, len: usize, buf: &mut Vec<u8>) -> io::Result<()> {
+    buf.clear();
+    reader.take(len as u64).read_to_end(buf)?;
+    Ok(())
+}
+
+fn encode_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn encode_time(time: Option<SystemTime>) -> u64 {
+    time.map_or(NEVER, |time| {
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    })
+}
+
+fn decode_time(millis: u64) -> Option<SystemTime> {
+    (millis != NEVER).then(|| UNIX_EPOCH + Duration::from_millis(millis))
+}
+
+impl<K, V> Cache<K, V>
+where
+    K: Eq + Hash + Clone + ByteSize + Send + Sync + 'static,
+    V: ByteSize + Send + Sync + 'static,
+{
+    /// Records every later change to the cache in the journal. A cache has one journal at most,
+    /// returns false if it has one already.
+    ///
+    /// Set it once the cache is restored, so the restored items are not recorded again.
+    pub fn set_journal(&self, journal: impl Journal<K, V> + 'static) -> bool {
+        self.journal.set(Box::new(journal)).is_ok()
+    }
+
+    /// Applies the changes of a journal on top of the items of the cache, e.g. the ones restored
+    /// from a snapshot. Items which expired meanwhile are dropped.
+    ///
+    /// The journal is read one record at a time, so give it a buffered reader.
+    pub fn replay_journal<R: Read>(&self, mut reader: R) -> io::Result<Replay>
+    where
+        K: From<Vec<u8>>,
+        V: From<Vec<u8>> + AsRef<[u8]>,
+    {
+        let mut header = Vec::new();
+        read_up_to(&mut reader, JOURNAL_HEADER.len(), &mut header)?;
+        if header != JOURNAL_HEADER {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "not a journal or an unsupported version",
+            ));
+        }
+        let mut replay = Replay::default();
+        let mut head = Vec::with_capacity(8);
+        let mut body = Vec::new();
+        loop {
+            read_up_to(&mut reader, 8, &mut head)?;
+            if head.is_empty() {
+                break;
+            }
+            let mut input = Input(&head);
+            let (len, checksum) = match (input.u32(), input.u32()) {
+                (Ok(len), Ok(checksum)) => (len as usize, checksum),
+                _ => return Ok(replay),
+            };
+            read_up_to(&mut reader, len, &mut body)?;
+            if body.len() < len
+                || crc32(CRC32_INIT, &body) ^ CRC32_INIT != checksum
+                || self.replay_change(&mut Input(&body)).is_err()
+            {
+                return Ok(replay);
+            }
+            replay.changes += 1;
+        }
+        replay.complete = true;
+        Ok(replay)
+    }
+
+    /// Applies a single record, fails if it can not be decoded.
+    fn replay_change(&self, input: &mut Input<'_>) -> Result<(), SnapshotError>
+    where
+        K: From<Vec<u8>>,
+        V: From<Vec<u8>> + AsRef<[u8]>,
+    {
+        match input.u8()? {
+            TAG_SET => {
+                let flag = input.u32()?;
+                let cas = input.u64()?;
+                let expires_at = decode_time(input.u64()?);
+                let idle = input.u64()?;
+                let key = K::from(input.bytes()?.to_vec());
+                let value = V::from(input.bytes()?.to_vec());
+                // later writes must not reuse the cas of a replayed item
+                self.cas_counter.fetch_max(cas, Ordering::Relaxed);
+                match self.remaining(expires_at) {
+                    Some(ttl) => {
+                        let mut value = Value::new(value, ttl, flag, cas, self.now());
+                        value.idle = (idle > 0).then(|| Duration::from_millis(idle));
+                        self.store(key, value);
+                    }
+                    // the value replaced the previous one, and expired since
+                    None => {
+                        self.remove(&key);
+                    }
+                }
+            }
+            TAG_TOUCH => {
+                let expires_at = decode_time(input.u64()?);
+                let key = K::from(input.bytes()?.to_vec());
+                match self.remaining(expires_at) {
+                    Some(ttl) => {
+                        if let Some(mut r) = self.map.get_mut(&key) {
+                            r.timestamp = ttl.map(|ttl| self.now() + ttl);
+                            self.schedule_expiry(r.key(), &r);
+                        }
+                    }
+                    None => {
+                        self.remove(&key);
+                    }
+                }
+            }
+            tag @ (TAG_APPEND | TAG_PREPEND) => {
+                let previous_cas = input.u64()?;
+                let cas = input.u64()?;
+                let key = K::from(input.bytes()?.to_vec());
+                let data = input.bytes()?;
+                self.cas_counter.fetch_max(cas, Ordering::Relaxed);
+                // the item expired since if it is gone, and the snapshot has the data already if
+                // the item has another cas
+                if let Some(mut r) = self.map.get_mut(&key) {
+                    if r.cas == previous_cas && !self.is_expired(&r) {
+                        let v = r.value_mut();
+                        let value = if tag == TAG_APPEND {
+                            [v.value.as_ref(), data].concat()
+                        } else {
+                            [data, v.value.as_ref()].concat()
+                        };
+                        self.counters.add_bytes(data.len() as i64);
+                        v.value = V::from(value);
+                        v.cas = cas;
+                        v.updated = self.now();
+                    }
+                }
+                self.evict();
+            }
+            TAG_DELETE => {
+                let key = K::from(input.bytes()?.to_vec());
+                self.remove(&key);
+            }
+            TAG_FLUSH => {
+                let at = decode_time(input.u64()?).unwrap_or(UNIX_EPOCH);
+                if at <= SystemTime::now() {
+                    self.clear();
+                } else {
+                    self.invalidate_before(at);
+                }
+            }
+            _ => return Err(SnapshotError::Corrupt("unknown record")),
+        }
+        if !input.0.is_empty() {
+            return Err(SnapshotError::Corrupt("record too long"));
+        }
+        Ok(())
+    }
+
+    /// The ttl left until a time of the journal, `Some(None)` if it is never, `None` if it has
+    /// passed.
+    fn remaining(&self, expires_at: Option<SystemTime>) -> Option<Option<Duration>> {
+        match expires_at {
+            None => Some(None),
+            Some(at) => at
+                .duration_since(SystemTime::now())
+                .ok()
+                .filter(|ttl| !ttl.is_zero())
+                .map(Some),
+        }
+    }
+
+    /// The system time of a time of the cache clock.
+    fn system_time(&self, time: Duration) -> SystemTime {
+        let now = self.now();
+        if time >= now {
+            SystemTime::now() + (time - now)
+        } else {
+            SystemTime::now() - (now - time)
+        }
+    }
+
+    /// Records a stored or updated value in the journal, if the cache has one. Like all the
+    /// `journal_` methods it is called under the lock of the key.
+    pub(crate) fn journal_set(&self, key: &K, v: &Value<V>) {
+        if let Some(journal) = self.journal.get() {
+            journal.record(Change::Set {
+                key,
+                value: &v.value,
+                flag: v.flag,
+                cas: v.cas,
+                expires_at: v.timestamp.map(|t| self.system_time(t)),
+                idle: v.idle,
+            });
+        }
+    }
+
+    pub(crate) fn journal_touch(&self, key: &K, v: &Value<V>) {
+        if let Some(journal) = self.journal.get() {
+            journal.record(Change::Touch {
+                key,
+                expires_at: v.timestamp.map(|t| self.system_time(t)),
+            });
+        }
+    }
+
+    /// Records data added after the value of an item if `append`, before it otherwise. The item
+    /// had `previous_cas` before.
+    pub(crate) fn journal_append(
+        &self,
+        key: &K,
+        v: &Value<V>,
+        previous_cas: u64,
+        data: &[u8],
+        append: bool,
+    ) {
+        if let Some(journal) = self.journal.get() {
+            let cas = v.cas;
+            journal.record(if append {
+                Change::Append {
+                    key,
+                    data,
+                    previous_cas,
+                    cas,
+                }
+            } else {
+                Change::Prepend {
+                    key,
+                    data,
+                    previous_cas,
+                    cas,
+                }
+            });
+        }
+    }
+
+    pub(crate) fn journal_delete(&self, key: &K) {
+        if let Some(journal) = self.journal.get() {
+            journal.record(Change::Delete { key });
+        }
+    }
+
+    pub(crate) fn journal_flush(&self, at: SystemTime) {
+        if let Some(journal) = self.journal.get() {
+            journal.record(Change::Flush { at });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    fn set<'a>(key: &'a Vec<u8>, value: &'a Bytes) -> Change<'a, Vec<u8>, Bytes> {
+        Change::Set {
+            key,
+            value,
+            flag: 3,
+            cas: 7,
+            expires_at: None,
+            idle: None,
+        }
+    }
+
+    fn journal(changes: &[Change<'_, Vec<u8>, Bytes>]) -> Vec<u8> {
+        let mut data = JOURNAL_HEADER.to_vec();
+        for change in changes {
+            change.encode(&mut data);
+        }
+        data
+    }
+
+    #[test]
+    fn round_trips() {
+        let (a, b, value) = (b"a".to_vec(), b"b".to_vec(), Bytes::from_static(b"1"));
+        let data = journal(&[
+            set(&a, &value),
+            set(&b, &value),
+            Change::Touch {
+                key: &b,
+                expires_at: Some(SystemTime::now() + Duration::from_secs(100)),
+            },
+            Change::Delete { key: &a },
+        ]);
+        let cache: Cache<Vec<u8>, Bytes> = Cache::new(None);
+        let replay = cache.replay_journal(data.as_slice()).unwrap();
+        assert_eq!(
+            replay,
+            Replay {
+                changes: 4,
+                complete: true
+            }
+        );
+        assert!(cache.get(b"a".as_slice()).is_none());
+        let b = cache.get(b"b".as_slice()).unwrap();
+        assert_eq!((b.get_flag(), b.get_cas()), (3, 7));
+        assert_eq!(b.into_value(), "1");
+        // cas uniques are not given out again
+        assert_eq!(cache.set(a, value, 0, 0), 8);
+    }
+
+    #[test]
+    fn replays_appends() {
+        let (a, value) = (b"a".to_vec(), Bytes::from_static(b"b"));
+        let data = journal(&[
+            set(&a, &value),
+            Change::Append {
+                key: &a,
+                data: b"c",
+                previous_cas: 7,
+                cas: 8,
+            },
+            Change::Prepend {
+                key: &a,
+                data: b"a",
+                previous_cas: 8,
+                cas: 9,
+            },
+        ]);
+        let cache: Cache<Vec<u8>, Bytes> = Cache::new(None);
+        assert!(cache.replay_journal(data.as_slice()).unwrap().complete);
+        let item = cache.get(b"a".as_slice()).unwrap();
+        assert_eq!((item.get_flag(), item.get_cas()), (3, 9));
+        assert_eq!(item.into_value(), "abc");
+        assert_eq!(
+            cache.stats().bytes,
+            cache.item_sizes(1).keys().sum::<usize>() as u64
+        );
+    }
+
+    /// Keeps the records of the changes in memory.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<u8>>>);
+
+    impl Journal<Vec<u8>, Bytes> for Recorder {
+        fn record(&self, change: Change<'_, Vec<u8>, Bytes>) {
+            change.encode(&mut self.0.lock().unwrap());
+        }
+    }
+
+    #[test]
+    fn does_not_replay_appends_twice() {
+        let cache: Cache<Vec<u8>, Bytes> = Cache::new(None);
+        cache.insert(b"k".to_vec(), Bytes::from_static(b"a"), 0);
+        let mut before = Vec::new();
+        cache.write_snapshot(&mut before).unwrap();
+        let recorder = Recorder::default();
+        recorder.0.lock().unwrap().extend_from_slice(JOURNAL_HEADER);
+        cache.set_journal(recorder.clone());
+        cache.append(b"k".as_slice(), b"b");
+        cache.prepend(b"k".as_slice(), b"c");
+        let mut after = Vec::new();
+        cache.write_snapshot(&mut after).unwrap();
+        let log = recorder.0.lock().unwrap().clone();
+
+        // whether the snapshot has the changes of the log or not
+        for snapshot in [before, after] {
+            let restored: Cache<Vec<u8>, Bytes> = Cache::new(None);
+            restored.read_snapshot(Cursor::new(snapshot)).unwrap();
+            assert!(restored.replay_journal(log.as_slice()).unwrap().complete);
+            let item = restored.get(b"k".as_slice()).unwrap();
+            assert_eq!(item.get_cas(), 3);
+            assert_eq!(item.into_value(), "cab");
+        }
+    }
+
+    #[test]
+    fn stops_at_a_torn_record() {
+        let (a, b, value) = (b"a".to_vec(), b"b".to_vec(), Bytes::from_static(b"1"));
+        let mut data = journal(&[set(&a, &value), set(&b, &value)]);
+        data.pop();
+        let cache: Cache<Vec<u8>, Bytes> = Cache::new(None);
+        let replay = cache.replay_journal(data.as_slice()).unwrap();
+        assert_eq!(
+            replay,
+            Replay {
+                changes: 1,
+                complete: false
+            }
+        );
+        assert!(cache.get(b"a".as_slice()).is_some());
+        assert!(cache.get(b"b".as_slice()).is_none());
+    }
+
+    #[test]
+    fn stops_at_a_record_not_matching_its_checksum() {
+        let (a, b, value) = (b"a".to_vec(), b"b".to_vec(), Bytes::from_static(b"1"));
+        let mut data = journal(&[set(&a, &value), set(&b, &value)]);
+        *data.last_mut().unwrap() ^= 1;
+        let cache: Cache<Vec<u8>, Bytes> = Cache::new(None);
+        let replay = cache.replay_journal(data.as_slice()).unwrap();
+        assert!(!replay.complete);
+        assert_eq!(replay.changes, 1);
+        assert!(cache.get(b"b".as_slice()).is_none());
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut data = journal(&[]);
+        *data.last_mut().unwrap() = 2;
+        let cache: Cache<Vec<u8>, Bytes> = Cache::new(None);
+        assert!(cache.replay_journal(data.as_slice()).is_err());
+    }
+}
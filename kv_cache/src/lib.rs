@@ -1,48 +1,521 @@
-use dashmap::mapref::one::Ref;
+mod builder;
+mod clock;
+mod compute;
+mod eviction;
+mod expiry;
+mod journal;
+mod listener;
+mod loader;
+mod meta;
+mod snapshot;
+mod stats;
+mod vacuum;
+
+pub use builder::CacheBuilder;
+pub use clock::{Clock, MockClock, MonotonicClock};
+pub use compute::{Compute, CurrentItem};
+
+pub use eviction::{key_hash, EvictionPolicy, Lfu, Lru, WTinyLfu};
+pub use journal::{Change, Journal, Replay, JOURNAL_HEADER};
+pub use listener::RemovalCause;
+pub use loader::LoadError;
+pub use meta::{
+    MetaArithmeticOptions, MetaDeleteOptions, MetaError, MetaGetOptions, MetaItem, MetaSetMode,
+    MetaSetOptions,
+};
+pub use snapshot::SnapshotError;
+pub use stats::CacheStats;
+pub use vacuum::VacuumHandle;
+
+use bytes::{Bytes, BytesMut};
+use dashmap::mapref::entry::{Entry, OccupiedEntry, VacantEntry};
 use dashmap::DashMap;
+use eviction::Eviction;
+use expiry::ExpiryIndex;
+use listener::Notifier;
+use loader::{Load, Loads};
 use log::debug;
+use stats::Counters;
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
 use std::hash::Hash;
-use std::ops::{Add, Deref};
-use std::sync::Arc;
-use std::thread;
-use std::time::{Duration, SystemTime};
+use std::mem;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use vacuum::Vacuum;
 
 pub struct Cache<K, V> {
     map: Arc<DashMap<K, Value<V>>>,
     default_ttl: Option<Duration>,
+    /// Idle timeout of the values stored without one.
+    time_to_idle: Option<Duration>,
+    cas_counter: Arc<AtomicU64>,
+    /// Values stored before this time (micros of the clock) are invalid once it has passed,
+    /// [`NO_FLUSH`] if no flush is pending.
+    oldest_live: Arc<AtomicU64>,
+    counters: Arc<Counters>,
+    /// Byte budget of the cache and the policy picking the items to evict to stay within it.
+    eviction: Option<Arc<Eviction<K>>>,
+    /// Keys by the time they expire, visited by the vacuum thread.
+    expiry: Arc<ExpiryIndex<K>>,
+    /// Stops the vacuum thread once the last clone is dropped, `None` in the clone owned by the
+    /// thread itself and if the cache has no thread.
+    vacuum: Option<Arc<Vacuum>>,
+    clock: Arc<dyn Clock>,
+    /// The running loaders of missing keys and the keys their loaders found missing.
+    loads: Arc<Loads<K, V>>,
+    /// How long a key found missing by a loader is not loaded again.
+    negative_ttl: Option<Duration>,
+    /// Passes the items leaving the cache to the removal listeners, `None` if there are none.
+    notifier: Option<Arc<Notifier<K, V>>>,
+    /// Records the changes to the cache once one is set, see [`Cache::set_journal`].
+    journal: Arc<OnceLock<Box<dyn Journal<K, V>>>>,
+}
+
+/// Approximate bookkeeping bytes an eviction policy keeps next to each copy of a key: its tick or
+/// use count and its share of the map or tree node.
+const POLICY_ENTRY_OVERHEAD: usize = 24;
+
+/// Value of `Value::scheduled` when the key is not in the expiry index, buckets start at 1.
+const NOT_SCHEDULED: u64 = 0;
+
+/// Value of `oldest_live` when no flush is pending, it never passes.
+const NO_FLUSH: u64 = u64::MAX;
+
+/// Approximate number of bytes taken by a key or a value, used for memory accounting.
+pub trait ByteSize {
+    fn byte_size(&self) -> usize;
+}
+
+impl ByteSize for Vec<u8> {
+    fn byte_size(&self) -> usize {
+        self.len()
+    }
+}
+
+impl ByteSize for Bytes {
+    fn byte_size(&self) -> usize {
+        self.len()
+    }
+}
+
+impl ByteSize for String {
+    fn byte_size(&self) -> usize {
+        self.len()
+    }
+}
+
+/// Error of a [`Cache::incr`] or [`Cache::decr`] call.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CounterError {
+    /// The key does not exist (or has expired).
+    NotFound,
+    /// The value is not a decimal representation of a 64-bit unsigned integer.
+    NonNumeric,
+}
+
+/// Result of a [`Cache::compare_and_swap`] call.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CasResult {
+    /// The cas unique matched and the new value was stored, with this cas unique.
+    Stored(u64),
+    /// The item has been modified since it was fetched, nothing was stored.
+    Exists,
+    /// The key does not exist (or has expired).
+    NotFound,
 }
 
 // 'static is used here which means the K and V *can* live as 'static as they will be also referenced by a long running thread
-impl<'a, K: 'a + Eq + Hash + Send + Sync + 'static, V: 'a + Send + Sync + 'static> Cache<K, V> {
+impl<
+        K: Eq + Hash + Clone + ByteSize + Send + Sync + 'static,
+        V: ByteSize + Send + Sync + 'static,
+    > Cache<K, V>
+{
     pub fn new(default_ttl: Option<Duration>) -> Cache<K, V> {
-        let m = DashMap::new();
-        let arc = Arc::new(m);
-        let map = arc.clone();
-
-        thread::spawn(move || loop {
-            let old_size = map.len();
-            map.retain(|_, v: &mut Value<V>| !v.is_expired());
-            debug!("vacuum expired keys, size {} -> {}", old_size, map.len());
-            thread::sleep(Duration::from_secs(10));
-        });
+        CacheBuilder {
+            default_ttl,
+            ..CacheBuilder::new()
+        }
+        .build()
+    }
+
+    /// Creates a cache which evicts the least recently used items once its items take more than
+    /// `max_bytes`, see [`CacheStats::bytes`] for how items are accounted. No limit if `None`.
+    pub fn with_max_bytes(default_ttl: Option<Duration>, max_bytes: Option<usize>) -> Cache<K, V> {
+        match max_bytes {
+            Some(max_bytes) => Self::with_eviction_policy(default_ttl, max_bytes, Lru::new()),
+            None => Self::new(default_ttl),
+        }
+    }
 
-        Cache {
-            map: arc,
+    /// Creates a cache which evicts the items picked by the policy once its items take more than
+    /// `max_bytes`.
+    pub fn with_eviction_policy(
+        default_ttl: Option<Duration>,
+        max_bytes: usize,
+        policy: impl EvictionPolicy<K> + 'static,
+    ) -> Cache<K, V> {
+        CacheBuilder {
             default_ttl,
+            ..CacheBuilder::new()
+        }
+        .eviction_policy(max_bytes, policy)
+        .build()
+    }
+
+    /// Configures a cache: its ttl, byte budget and how expired items are reclaimed.
+    pub fn builder() -> CacheBuilder<K, V> {
+        CacheBuilder::new()
+    }
+
+    fn from_builder(builder: CacheBuilder<K, V>) -> Cache<K, V> {
+        let mut cache = Cache {
+            map: Arc::new(DashMap::new()),
+            default_ttl: builder.default_ttl,
+            time_to_idle: builder.time_to_idle,
+            cas_counter: Arc::new(AtomicU64::new(0)),
+            oldest_live: Arc::new(AtomicU64::new(NO_FLUSH)),
+            counters: Arc::new(Counters::default()),
+            eviction: builder.eviction.map(Arc::new),
+            expiry: Arc::new(ExpiryIndex::new()),
+            vacuum: None,
+            clock: builder.clock,
+            loads: Arc::new(Mutex::new(HashMap::new())),
+            negative_ttl: builder.negative_ttl,
+            notifier: builder
+                .copy_value
+                .map(|copy| Arc::new(Notifier::spawn(builder.listeners, copy))),
+            journal: Arc::new(OnceLock::new()),
+        };
+        if let Some(interval) = builder.sweep_interval {
+            // the thread must not own the vacuum itself, or the cache would never be dropped
+            let vacuum = cache.clone();
+            cache.vacuum = Some(Arc::new(Vacuum::spawn(interval, move || vacuum.sweep())));
+        }
+        cache
+    }
+
+    /// Removes the expired items whose keys are due in the expiry index, and once a flush has
+    /// taken effect, all items stored before it.
+    ///
+    /// The work is proportional to the number of keys expiring since the last sweep, only a flush
+    /// walks the whole map, once. Runs on the vacuum thread, call it periodically for caches built
+    /// [`CacheBuilder::without_vacuum_thread`].
+    pub fn sweep(&self) {
+        let start = Instant::now();
+        let mut reclaimed = 0;
+        let now = self.now();
+        for (bucket, key) in self.expiry.take_due(now) {
+            // the key may have been removed, stored again or touched since it was scheduled
+            let removed = self.map.remove_if(&key, |k, v| {
+                // unless the value was scheduled earlier, this was its pending entry
+                let _ = v.scheduled.compare_exchange(
+                    bucket,
+                    NOT_SCHEDULED,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                );
+                let expired = v.is_expired(now);
+                if expired {
+                    self.forget(k);
+                } else {
+                    self.schedule_expiry(k, v);
+                }
+                expired
+            });
+            if let Some((k, v)) = removed {
+                self.reclaim(&k, &v);
+                reclaimed += 1;
+            }
+        }
+
+        let flush = self.oldest_live.load(Ordering::Relaxed);
+        if flush <= micros(now) {
+            self.map.retain(|k, v| {
+                if !self.is_expired(v) {
+                    return true;
+                }
+                self.forget(k);
+                self.reclaim(k, v);
+                reclaimed += 1;
+                false
+            });
+            // nothing stored before the flush is left, unless another flush was asked for meanwhile
+            let _ = self.oldest_live.compare_exchange(
+                flush,
+                NO_FLUSH,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
         }
+
+        self.loads
+            .lock()
+            .unwrap()
+            .retain(|_, load| !matches!(load, Load::Missing(until) if *until <= now));
+
+        let elapsed = start.elapsed();
+        self.counters.record_sweep(reclaimed, elapsed);
+        debug!(
+            "vacuum reclaimed {} expired keys in {:?}, size {}",
+            reclaimed,
+            elapsed,
+            self.map.len()
+        );
     }
 
-    pub fn get(&'a self, key: &K) -> Option<RefWrapper<'a, K, V>> {
-        self.map
-            .get(key)
-            .and_then(|r| if r.is_expired() { None } else { Some(r.into()) })
+    /// Accounts for an expired or flushed item removed from the map.
+    fn reclaim(&self, k: &K, v: &Value<V>) {
+        self.counters.remove_item(self.item_size(k, v));
+        self.record_expiration(v);
+        self.notify(k, &v.value, self.removal_cause(v, RemovalCause::Expired));
+    }
+
+    /// Hands an item leaving the cache to the removal listeners, if there are any.
+    fn notify(&self, key: &K, value: &V, cause: RemovalCause) {
+        if let Some(notifier) = &self.notifier {
+            if !notifier.notify(key, value, cause) {
+                self.counters
+                    .dropped_notifications
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Why a value leaves the map: the given cause while it is live, otherwise its ttl or a flush.
+    fn removal_cause(&self, v: &Value<V>, live: RemovalCause) -> RemovalCause {
+        let now = self.now();
+        if v.is_expired(now) {
+            RemovalCause::Expired
+        } else if v.is_flushed(&self.oldest_live, now) {
+            RemovalCause::Explicit
+        } else {
+            live
+        }
+    }
+
+    /// Counts a value dropped from the map if its ttl has passed, flushed values do not count.
+    fn record_expiration(&self, v: &Value<V>) {
+        if !v.is_expired(self.now()) {
+            return;
+        }
+        self.counters.expirations.fetch_add(1, Ordering::Relaxed);
+        if !v.fetched.load(Ordering::Relaxed) {
+            self.counters
+                .expired_unfetched
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Adds a key to the expiry index if its value has a deadline and is not pending in an
+    /// earlier bucket already, the sweep schedules it again once that bucket is due.
+    ///
+    /// Called whenever a value gets a new deadline, the index does not need to forget the old one.
+    fn schedule_expiry(&self, key: &K, v: &Value<V>) {
+        if let Some(deadline) = v.next_expiry() {
+            let bucket = ExpiryIndex::<K>::bucket(deadline);
+            let scheduled = v.scheduled.load(Ordering::Relaxed);
+            if scheduled == NOT_SCHEDULED || bucket < scheduled {
+                v.scheduled.store(bucket, Ordering::Relaxed);
+                self.expiry.insert(key, bucket);
+            }
+        }
+    }
+
+    /// Whether a lookup found a live value, recording the hit or miss and marking the value as
+    /// fetched.
+    fn lookup<Q>(&self, key: &Q, entry: Option<(&K, &Value<V>)>) -> bool
+    where
+        Q: Hash + ?Sized,
+    {
+        let hit = match entry {
+            Some((k, v)) if !self.is_expired(v) => {
+                v.fetched.store(true, Ordering::Relaxed);
+                v.accessed.store(micros(self.now()), Ordering::Relaxed);
+                self.record_hit(k);
+                true
+            }
+            _ => {
+                if let Some(eviction) = &self.eviction {
+                    eviction.record_miss(key_hash(key));
+                }
+                false
+            }
+        };
+        self.counters.record_lookup(hit);
+        hit
+    }
+
+    /// Tells the eviction policy about a write of a key.
+    ///
+    /// Must be called while holding the lock on the shard of the key, so the map and the eviction
+    /// policy agree on which keys exist.
+    fn record_use(&self, key: &K) {
+        if let Some(eviction) = &self.eviction {
+            eviction.policy().record_use(key);
+        }
+    }
+
+    /// Tells the eviction policy about a read of a key, which it learns of later.
+    fn record_hit(&self, key: &K) {
+        if let Some(eviction) = &self.eviction {
+            eviction.record_hit(key);
+        }
+    }
+
+    /// Drops a key from the eviction policy, with the same locking rule as [`Cache::record_use`].
+    fn forget(&self, key: &K) {
+        if let Some(eviction) = &self.eviction {
+            eviction.policy().remove(key);
+        }
+    }
+
+    /// Evicts the items picked by the eviction policy until the cache fits in its byte budget
+    /// again.
+    ///
+    /// Must not be called while holding a lock on the map, as the victims may live in any shard.
+    fn evict(&self) {
+        let eviction = match &self.eviction {
+            Some(eviction) => eviction,
+            None => return,
+        };
+        while self.counters.bytes.load(Ordering::Relaxed) > eviction.max_bytes as i64 {
+            let key = match eviction.drained_policy().evict() {
+                Some(key) => key,
+                None => break,
+            };
+            // a key stored again since it was picked is tracked by the policy again, it is not
+            // the one to evict anymore
+            let removed = self
+                .map
+                .remove_if(&key, |k, _| !eviction.policy().contains(k));
+            if let Some((k, v)) = removed {
+                self.counters.remove_item(self.item_size(&k, &v));
+                if !self.is_expired(&v) {
+                    self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    self.record_expiration(&v);
+                }
+                self.notify(&k, &v.value, self.removal_cause(&v, RemovalCause::Evicted));
+            }
+        }
+    }
+
+    /// Whether a value is expired, either by its own ttl or by a flush.
+    fn is_expired(&self, v: &Value<V>) -> bool {
+        let now = self.now();
+        v.is_expired(now) || v.is_flushed(&self.oldest_live, now)
+    }
+
+    fn now(&self) -> Duration {
+        self.clock.now()
+    }
+
+    /// Returns a new unique cas value, every write to the cache gets one.
+    fn next_cas(&self) -> u64 {
+        self.cas_counter.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Gets a key. The value is handed out as an owned handle, so the shard lock is released
+    /// before the caller uses it, with [`Bytes`] values this only bumps a reference count.
+    ///
+    /// Like all lookups it takes any borrowed form of the key, e.g. a `&[u8]` for `Vec<u8>` keys.
+    pub fn get<Q>(&self, key: &Q) -> Option<Item<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        let r = self.map.get(key);
+        if self.lookup(key, r.as_ref().map(|r| r.pair())) {
+            r.map(|r| Item::from(&*r))
+        } else {
+            None
+        }
+    }
+
+    /// Gets many keys at once, the result has one entry per key in the same order as the keys.
+    ///
+    /// Keys are grouped by the shard they live in, so each shard is only read locked once and the
+    /// lock is released before moving on to the next shard.
+    pub fn get_many<Q>(&self, keys: &[&Q]) -> Vec<Option<Item<V>>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        let mut order: Vec<(usize, usize)> = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| (self.map.determine_map(key), i))
+            .collect();
+        order.sort_unstable();
+
+        let mut items: Vec<Option<Item<V>>> = keys.iter().map(|_| None).collect();
+        let shards = self.map.shards();
+        let mut start = 0;
+        while start < order.len() {
+            let shard_index = order[start].0;
+            let shard = shards[shard_index].read();
+            while start < order.len() && order[start].0 == shard_index {
+                let i = order[start].1;
+                let entry = shard.get_key_value(keys[i]).map(|(k, v)| (k, v.get()));
+                if self.lookup(keys[i], entry) {
+                    items[i] = entry.map(|(_, v)| Item::from(v));
+                }
+                start += 1;
+            }
+        }
+        items
+    }
+
+    /// Gets a key and updates its ttl in place in one step.
+    ///
+    /// The value is cloned out, so the shard lock is released before the caller writes it anywhere.
+    pub fn get_and_touch<Q>(&self, key: &Q, default_ttl_seconds: u32) -> Option<Item<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        let mut r = self.map.get_mut(key);
+        if self.lookup(key, r.as_ref().map(|r| r.pair())) {
+            let (k, v) = r.as_mut().unwrap().pair_mut();
+            v.set_ttl(default_ttl_seconds, self.now());
+            self.schedule_expiry(k, v);
+            self.journal_touch(k, v);
+            Some(Item::from(&*v))
+        } else {
+            None
+        }
+    }
+
+    /// Updates the ttl of a key without touching its value. Returns true if the key exists.
+    pub fn touch<Q>(&self, key: &Q, default_ttl_seconds: u32) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.map.get_mut(key) {
+            Some(mut r) if !self.is_expired(&r) => {
+                r.set_ttl(default_ttl_seconds, self.now());
+                r.accessed.store(micros(self.now()), Ordering::Relaxed);
+                self.schedule_expiry(r.key(), &r);
+                self.journal_touch(r.key(), &r);
+                self.record_use(r.key());
+                true
+            }
+            _ => false,
+        }
     }
 
     /// Inserts a key and a value into the map. Returns the old value associated with the key if there was one.
     pub fn insert(&self, key: K, value: V, flag: u32) -> Option<V> {
-        self.map
-            .insert(key, Value::new(value, self.default_ttl, flag))
-            .map(|v| v.value) // return old value if exist
+        let value = Value::new(value, self.default_ttl, flag, self.next_cas(), self.now());
+        self.store(key, value)
     }
 
     /// Inserts a key and a value into the map. Returns the old value associated with the key if there was one.
@@ -53,21 +526,381 @@ impl<'a, K: 'a + Eq + Hash + Send + Sync + 'static, V: 'a + Send + Sync + 'stati
         default_ttl_seconds: u32,
         flag: u32,
     ) -> Option<V> {
-        self.map
-            .insert(key, Value::new_with_ttl(value, default_ttl_seconds, flag))
-            .map(|v| v.value) // return old value if exist
+        let value = Value::new_with_ttl(
+            value,
+            default_ttl_seconds,
+            flag,
+            self.next_cas(),
+            self.now(),
+        );
+        self.store(key, value)
+    }
+
+    /// Stores a key and a value like `insert_with_ttl`, for a set command. Returns the cas unique
+    /// of the stored item.
+    pub fn set(&self, key: K, value: V, default_ttl_seconds: u32, flag: u32) -> u64 {
+        let cas = self.next_cas();
+        let value = Value::new_with_ttl(value, default_ttl_seconds, flag, cas, self.now());
+        self.store(key, value);
+        cas
+    }
+
+    /// Inserts a key and a value which expires once it has not been accessed for `idle_seconds`,
+    /// or once its ttl has passed if it has one. Reads and touches refresh it. Returns the cas
+    /// unique of the stored item.
+    pub fn insert_with_idle(
+        &self,
+        key: K,
+        value: V,
+        idle_seconds: u32,
+        default_ttl_seconds: u32,
+        flag: u32,
+    ) -> u64 {
+        let cas = self.next_cas();
+        let mut value = Value::new_with_ttl(value, default_ttl_seconds, flag, cas, self.now());
+        value.set_idle(idle_seconds);
+        self.store(key, value);
+        cas
     }
 
+    fn store(&self, key: K, value: Value<V>) -> Option<V> {
+        let old = self.put_entry(self.map.entry(key), value);
+        self.evict();
+        old
+    }
+
+    /// Puts a value into an entry whether it is occupied or not. Returns the old value.
+    fn put_entry(&self, entry: Entry<K, Value<V>, RandomState>, value: Value<V>) -> Option<V> {
+        match entry {
+            Entry::Occupied(mut o) => Some(self.replace_entry(&mut o, value)),
+            Entry::Vacant(v) => {
+                self.insert_entry(v, value);
+                None
+            }
+        }
+    }
+
+    /// Replaces the value of an occupied entry, keeping the byte accounting up to date.
+    /// Returns the old value.
+    fn replace_entry(
+        &self,
+        o: &mut OccupiedEntry<K, Value<V>, RandomState>,
+        mut value: Value<V>,
+    ) -> V {
+        value.idle = value.idle.or(self.time_to_idle);
+        // the key stays pending where the old value was
+        value.scheduled = AtomicU64::new(o.get().scheduled.load(Ordering::Relaxed));
+        let size = value.value.byte_size() as i64;
+        self.record_use(o.key());
+        self.schedule_expiry(o.key(), &value);
+        self.journal_set(o.key(), &value);
+        let old = o.insert(value);
+        self.counters.add_bytes(size - old.value.byte_size() as i64);
+        self.counters.record_store(!self.is_expired(&old));
+        self.record_expiration(&old);
+        self.notify(
+            o.key(),
+            &old.value,
+            self.removal_cause(&old, RemovalCause::Replaced),
+        );
+        old.value
+    }
+
+    /// Inserts a value into a vacant entry, keeping the byte accounting up to date.
+    fn insert_entry(&self, v: VacantEntry<K, Value<V>, RandomState>, mut value: Value<V>) {
+        value.idle = value.idle.or(self.time_to_idle);
+        self.counters.add_item(self.item_size(v.key(), &value));
+        self.counters.record_store(false);
+        self.record_use(v.key());
+        self.schedule_expiry(v.key(), &value);
+        self.journal_set(v.key(), &value);
+        v.insert(value);
+    }
+
+    /// Inserts a key and a value into the map only if the key does not exist yet (or has expired).
+    /// Returns the cas unique of the stored item, `None` if nothing was stored.
+    pub fn add(&self, key: K, value: V, default_ttl_seconds: u32, flag: u32) -> Option<u64> {
+        let cas = self.next_cas();
+        let value = Value::new_with_ttl(value, default_ttl_seconds, flag, cas, self.now());
+        let stored = match self.map.entry(key) {
+            Entry::Occupied(o) if !self.is_expired(o.get()) => None,
+            Entry::Occupied(mut o) => {
+                self.replace_entry(&mut o, value);
+                Some(cas)
+            }
+            Entry::Vacant(v) => {
+                self.insert_entry(v, value);
+                Some(cas)
+            }
+        };
+        self.evict();
+        stored
+    }
+
+    /// Replaces the value of a key only if the key already exists (and has not expired).
+    /// Returns the cas unique of the stored item, `None` if nothing was stored.
+    pub fn replace(&self, key: K, value: V, default_ttl_seconds: u32, flag: u32) -> Option<u64> {
+        let stored = match self.map.entry(key) {
+            Entry::Occupied(mut o) if !self.is_expired(o.get()) => {
+                let cas = self.next_cas();
+                let value = Value::new_with_ttl(value, default_ttl_seconds, flag, cas, self.now());
+                self.replace_entry(&mut o, value);
+                Some(cas)
+            }
+            _ => None,
+        };
+        self.evict();
+        stored
+    }
+
+    /// Replaces the value of a key only if its cas unique still equals the given one, i.e. nobody
+    /// else has updated the key since it was fetched.
+    pub fn compare_and_swap(
+        &self,
+        key: K,
+        value: V,
+        default_ttl_seconds: u32,
+        flag: u32,
+        cas: u64,
+    ) -> CasResult {
+        let result = match self.map.entry(key) {
+            Entry::Occupied(o) if self.is_expired(o.get()) => CasResult::NotFound,
+            Entry::Occupied(o) if o.get().cas != cas => CasResult::Exists,
+            Entry::Occupied(mut o) => {
+                let cas = self.next_cas();
+                let value = Value::new_with_ttl(value, default_ttl_seconds, flag, cas, self.now());
+                self.replace_entry(&mut o, value);
+                CasResult::Stored(cas)
+            }
+            Entry::Vacant(_) => CasResult::NotFound,
+        };
+        self.evict();
+        result
+    }
+
+    /// Removes a key from the map. Returns the value associated with the key if there was one
+    /// that had not expired yet.
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let removed = self.map.remove_if(key, |k, _| {
+            self.forget(k);
+            self.journal_delete(k);
+            true
+        });
+        removed.and_then(|(k, v)| {
+            self.counters.remove_item(self.item_size(&k, &v));
+            self.notify(&k, &v.value, self.removal_cause(&v, RemovalCause::Explicit));
+            if self.is_expired(&v) {
+                None
+            } else {
+                Some(v.value)
+            }
+        })
+    }
+
+    /// Removes all keys from the map right away, this also cancels a pending
+    /// [`Cache::invalidate_before`] and forgets the keys loaders found missing.
+    pub fn clear(&self) {
+        self.journal_flush(SystemTime::now());
+        self.oldest_live.store(NO_FLUSH, Ordering::Relaxed);
+        self.loads
+            .lock()
+            .unwrap()
+            .retain(|_, load| matches!(load, Load::InFlight(_)));
+        self.map.retain(|k, v| {
+            self.counters.remove_item(self.item_size(k, v));
+            self.notify(k, &v.value, self.removal_cause(v, RemovalCause::Explicit));
+            self.forget(k);
+            false
+        });
+    }
+
+    /// Invalidates all values stored before the given time, once that time has passed. Values
+    /// stored afterwards are not affected. Use [`Cache::clear`] to flush right away.
+    ///
+    /// The time is taken as a delay from now, so the flush happens on time even if the system
+    /// time changes meanwhile.
+    pub fn invalidate_before(&self, time: SystemTime) {
+        self.journal_flush(time);
+        let delay = time.duration_since(SystemTime::now()).unwrap_or_default();
+        self.oldest_live
+            .store(micros(self.now() + delay), Ordering::Relaxed);
+    }
+}
+
+impl<K: Eq + Hash, V> Cache<K, V> {
     pub fn len(&self) -> usize {
         self.map.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// The byte budget of the cache, `None` if it is unbounded.
+    pub fn max_bytes(&self) -> Option<usize> {
+        self.eviction.as_ref().map(|eviction| eviction.max_bytes)
+    }
+
+    /// Stops the vacuum thread on demand, `None` if the cache was built without one.
+    pub fn vacuum_handle(&self) -> Option<VacuumHandle> {
+        self.vacuum.as_ref().map(|vacuum| vacuum.handle())
+    }
+
+    /// Returns a snapshot of the cache statistics, cheap enough to be taken on every request as
+    /// it only reads atomic counters.
+    pub fn stats(&self) -> CacheStats {
+        self.counters.snapshot()
+    }
+
+    /// Resets the event counters: hits, misses, stores, evictions and expirations.
+    pub fn reset_stats(&self) {
+        self.counters.reset();
+    }
+
+    /// Returns how many items there are per size, sizes are rounded up to a multiple of
+    /// `bucket` bytes. Walks the whole map so it is expensive on large caches.
+    pub fn item_sizes(&self, bucket: usize) -> BTreeMap<usize, u64>
+    where
+        K: ByteSize,
+        V: ByteSize,
+    {
+        let mut sizes = BTreeMap::new();
+        for r in self.map.iter() {
+            let size = self.item_size(r.key(), r.value()) as usize;
+            *sizes.entry(size.div_ceil(bucket) * bucket).or_insert(0) += 1;
+        }
+        sizes
+    }
+}
+
+/// Values are immutable as readers may still hold them, updates replace them with a new buffer.
+impl<K: Eq + Hash + Clone + ByteSize + Send + Sync + 'static> Cache<K, Bytes> {
+    /// Appends data to the value of an existing key, keeping its flag and ttl.
+    /// Returns the new cas unique of the item, `None` if the key does not exist.
+    pub fn append<Q>(&self, key: &Q, data: &[u8]) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.update_if_present(
+            key,
+            |item| Compute::Set {
+                value: concat(item.value, data),
+                flag: item.flag,
+            },
+            |cache, key, v, previous_cas| cache.journal_append(key, v, previous_cas, data, true),
+        )
+        .map(|item| item.get_cas())
+    }
+
+    /// Prepends data to the value of an existing key, keeping its flag and ttl.
+    /// Returns the new cas unique of the item, `None` if the key does not exist.
+    pub fn prepend<Q>(&self, key: &Q, data: &[u8]) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.update_if_present(
+            key,
+            |item| Compute::Set {
+                value: concat(data, item.value),
+                flag: item.flag,
+            },
+            |cache, key, v, previous_cas| cache.journal_append(key, v, previous_cas, data, false),
+        )
+        .map(|item| item.get_cas())
+    }
+
+    /// Increments the decimal number stored at a key by delta, wrapping around at 2^64.
+    /// Returns the new value and the new cas unique of the item.
+    pub fn incr<Q>(&self, key: &Q, delta: u64) -> Result<(u64, u64), CounterError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.update_counter(key, |n| n.wrapping_add(delta))
+    }
+
+    /// Decrements the decimal number stored at a key by delta, stopping at 0.
+    /// Returns the new value and the new cas unique of the item.
+    pub fn decr<Q>(&self, key: &Q, delta: u64) -> Result<(u64, u64), CounterError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.update_counter(key, |n| n.saturating_sub(delta))
+    }
+
+    fn update_counter<Q>(
+        &self,
+        key: &Q,
+        f: impl FnOnce(u64) -> u64,
+    ) -> Result<(u64, u64), CounterError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut result = Err(CounterError::NotFound);
+        let item = self.compute_if_present(key, |item| {
+            let n = std::str::from_utf8(item.value)
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok());
+            match n {
+                Some(n) => {
+                    let n = f(n);
+                    result = Ok(n);
+                    Compute::Set {
+                        value: Bytes::from(n.to_string()),
+                        flag: item.flag,
+                    }
+                }
+                None => {
+                    result = Err(CounterError::NonNumeric);
+                    Compute::Keep
+                }
+            }
+        });
+        result.map(|n| (n, item.map_or(0, |item| item.get_cas())))
+    }
+}
+
+impl<K: ByteSize, V: ByteSize> Cache<K, V> {
+    /// Bytes accounted for an item: its key, its value, the fixed size of the map entry and the
+    /// copies of the key kept by the eviction policy.
+    fn item_size(&self, key: &K, value: &Value<V>) -> i64 {
+        let key_size = key.byte_size() + mem::size_of::<K>();
+        let copies = self
+            .eviction
+            .as_ref()
+            .map_or(0, |eviction| eviction.key_copies);
+        (key_size * (1 + copies)
+            + POLICY_ENTRY_OVERHEAD * copies
+            + value.value.byte_size()
+            + mem::size_of::<Value<V>>()) as i64
+    }
 }
 
 impl<K: Eq + Hash + Send + Sync + 'static, V: Send + Sync + 'static> Clone for Cache<K, V> {
     fn clone(&self) -> Self {
         Self {
             map: self.map.clone(),
-            default_ttl: self.default_ttl.clone(),
+            default_ttl: self.default_ttl,
+            time_to_idle: self.time_to_idle,
+            cas_counter: self.cas_counter.clone(),
+            oldest_live: self.oldest_live.clone(),
+            counters: self.counters.clone(),
+            eviction: self.eviction.clone(),
+            expiry: self.expiry.clone(),
+            vacuum: self.vacuum.clone(),
+            clock: self.clock.clone(),
+            loads: self.loads.clone(),
+            negative_ttl: self.negative_ttl,
+            notifier: self.notifier.clone(),
+            journal: self.journal.clone(),
         }
     }
 }
@@ -75,57 +908,259 @@ impl<K: Eq + Hash + Send + Sync + 'static, V: Send + Sync + 'static> Clone for C
 struct Value<V> {
     value: V,
     flag: u32,
-    timestamp: Option<SystemTime>,
+    cas: u64,
+    /// When the value expires, on the clock of the cache.
+    timestamp: Option<Duration>,
+    /// When the value was last stored or modified, used to tell whether it was flushed.
+    updated: Duration,
+    /// Whether the value was ever fetched, for the expired_unfetched statistic.
+    fetched: AtomicBool,
+    /// When the value was last fetched (micros of the clock).
+    accessed: AtomicU64,
+    /// The value expires once it has not been fetched for this long.
+    idle: Option<Duration>,
+    /// The earliest bucket of the expiry index the key is pending in, `NOT_SCHEDULED` if none.
+    scheduled: AtomicU64,
+    /// Marked as stale by an invalidation, the value is still served until it is recached.
+    stale: bool,
+    /// Whether somebody already won the right to recache this value.
+    win_sent: bool,
 }
 
 impl<V> Value<V> {
-    pub fn new(value: V, ttl: Option<Duration>, flag: u32) -> Self {
+    pub fn new(value: V, ttl: Option<Duration>, flag: u32, cas: u64, now: Duration) -> Self {
         Value {
             value,
             flag,
-            timestamp: ttl.map(|ttl| SystemTime::now().add(ttl)),
+            cas,
+            timestamp: ttl.map(|ttl| now + ttl),
+            updated: now,
+            fetched: AtomicBool::new(false),
+            accessed: AtomicU64::new(micros(now)),
+            idle: None,
+            scheduled: AtomicU64::new(NOT_SCHEDULED),
+            stale: false,
+            win_sent: false,
         }
     }
 
-    pub fn new_with_ttl(value: V, default_ttl_seconds: u32, flag: u32) -> Self {
+    pub fn new_with_ttl(
+        value: V,
+        default_ttl_seconds: u32,
+        flag: u32,
+        cas: u64,
+        now: Duration,
+    ) -> Self {
         Value {
             value,
             flag,
-            timestamp: if default_ttl_seconds == 0 {
-                None
-            } else {
-                Some(SystemTime::now().add(Duration::from_secs(default_ttl_seconds as u64)))
-            },
+            cas,
+            timestamp: Self::deadline(default_ttl_seconds, now),
+            updated: now,
+            fetched: AtomicBool::new(false),
+            accessed: AtomicU64::new(micros(now)),
+            idle: None,
+            scheduled: AtomicU64::new(NOT_SCHEDULED),
+            stale: false,
+            win_sent: false,
+        }
+    }
+
+    pub fn set_ttl(&mut self, default_ttl_seconds: u32, now: Duration) {
+        self.timestamp = Self::deadline(default_ttl_seconds, now);
+    }
+
+    /// An idle timeout of 0 leaves the value to the idle timeout of the cache, if it has one.
+    pub fn set_idle(&mut self, idle_seconds: u32) {
+        self.idle = (idle_seconds > 0).then(|| Duration::from_secs(idle_seconds as u64));
+    }
+
+    /// A ttl of 0 means the value never expires.
+    fn deadline(default_ttl_seconds: u32, now: Duration) -> Option<Duration> {
+        if default_ttl_seconds == 0 {
+            None
+        } else {
+            Some(now + Duration::from_secs(default_ttl_seconds as u64))
         }
     }
 
-    pub fn is_expired(&self) -> bool {
-        self.timestamp
-            .map(|t| t.lt(&SystemTime::now()))
-            .unwrap_or(false)
+    /// Remaining seconds until the value expires, None if it never expires.
+    pub fn remaining_ttl(&self, now: Duration) -> Option<u64> {
+        self.timestamp.map(|t| {
+            let remaining = t.saturating_sub(now);
+            // rounded up so a freshly stored value reports its full ttl
+            remaining.as_secs() + (remaining.subsec_nanos() > 0) as u64
+        })
+    }
+
+    /// When the value expires unless it is fetched or touched meanwhile, None if it never
+    /// expires.
+    pub fn next_expiry(&self) -> Option<Duration> {
+        let idle_deadline = self
+            .idle
+            .map(|idle| Duration::from_micros(self.accessed.load(Ordering::Relaxed)) + idle);
+        match (self.timestamp, idle_deadline) {
+            (Some(t), Some(idle)) => Some(t.min(idle)),
+            (t, idle) => t.or(idle),
+        }
+    }
+
+    pub fn is_expired(&self, now: Duration) -> bool {
+        self.next_expiry().is_some_and(|t| t < now)
+    }
+
+    /// Whether the value was stored before a flush that has already taken effect.
+    pub fn is_flushed(&self, oldest_live: &AtomicU64, now: Duration) -> bool {
+        let oldest_live = oldest_live.load(Ordering::Relaxed);
+        oldest_live <= micros(now) && micros(self.updated) <= oldest_live
     }
 }
 
-pub struct RefWrapper<'a, K, V> {
-    inner: Ref<'a, K, Value<V>>,
+fn micros(time: Duration) -> u64 {
+    time.as_micros() as u64
+}
+
+fn concat(head: &[u8], tail: &[u8]) -> Bytes {
+    let mut bytes = BytesMut::with_capacity(head.len() + tail.len());
+    bytes.extend_from_slice(head);
+    bytes.extend_from_slice(tail);
+    bytes.freeze()
+}
+
+/// An owned handle on a value with its metadata, not holding any lock on the map.
+#[derive(Clone)]
+pub struct Item<V> {
+    value: V,
+    flag: u32,
+    cas: u64,
 }
 
-impl<'a, K: Eq + Hash, V> RefWrapper<'a, K, V> {
+impl<V> Item<V> {
     pub fn get_flag(&self) -> u32 {
-        return self.inner.deref().flag;
+        self.flag
+    }
+
+    pub fn get_cas(&self) -> u64 {
+        self.cas
+    }
+
+    pub fn into_value(self) -> V {
+        self.value
     }
 }
 
-impl<'a, K: Eq + Hash, V> Deref for RefWrapper<'a, K, V> {
+impl<V> Deref for Item<V> {
     type Target = V;
 
     fn deref(&self) -> &V {
-        &self.inner.deref().value
+        &self.value
     }
 }
 
-impl<'a, K: Eq + Hash, V> From<Ref<'a, K, Value<V>>> for RefWrapper<'a, K, V> {
-    fn from(inner: Ref<'a, K, Value<V>>) -> Self {
-        RefWrapper { inner }
+impl<V: Clone> From<&Value<V>> for Item<V> {
+    fn from(v: &Value<V>) -> Self {
+        Item {
+            value: v.value.clone(),
+            flag: v.flag,
+            cas: v.cas,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(clock: &MockClock) -> Cache<String, String> {
+        Cache::builder()
+            .clock(clock.clone())
+            .without_vacuum_thread()
+            .build()
+    }
+
+    fn get(cache: &Cache<String, String>, key: &str) -> Option<String> {
+        cache.get(key).map(Item::into_value)
+    }
+
+    #[test]
+    fn expires_after_ttl() {
+        let clock = MockClock::new();
+        let cache = cache(&clock);
+        cache.insert_with_ttl("a".into(), "1".into(), 5, 0);
+        cache.insert_with_ttl("b".into(), "2".into(), 0, 0);
+        clock.advance(Duration::from_secs(4));
+        assert_eq!(get(&cache, "a").as_deref(), Some("1"));
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(get(&cache, "a"), None);
+        assert_eq!(get(&cache, "b").as_deref(), Some("2"));
+        // not reclaimed until swept
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn reads_refresh_idle_items() {
+        let clock = MockClock::new();
+        let cache = cache(&clock);
+        cache.insert_with_idle("a".into(), "1".into(), 10, 0, 0);
+        for _ in 0..3 {
+            clock.advance(Duration::from_secs(6));
+            assert_eq!(get(&cache, "a").as_deref(), Some("1"));
+        }
+        clock.advance(Duration::from_secs(11));
+        assert_eq!(get(&cache, "a"), None);
+    }
+
+    #[test]
+    fn idle_items_expire_at_their_ttl() {
+        let clock = MockClock::new();
+        let cache = cache(&clock);
+        cache.insert_with_idle("a".into(), "1".into(), 10, 15, 0);
+        clock.advance(Duration::from_secs(8));
+        assert!(get(&cache, "a").is_some());
+        clock.advance(Duration::from_secs(8));
+        assert_eq!(get(&cache, "a"), None);
+    }
+
+    #[test]
+    fn invalidates_before_a_later_time() {
+        let clock = MockClock::new();
+        let cache = cache(&clock);
+        cache.insert("a".into(), "1".into(), 0);
+        cache.invalidate_before(SystemTime::now() + Duration::from_secs(10));
+        clock.advance(Duration::from_secs(5));
+        cache.insert("b".into(), "2".into(), 0);
+        assert!(get(&cache, "a").is_some());
+        assert!(get(&cache, "b").is_some());
+
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(get(&cache, "a"), None);
+        assert_eq!(get(&cache, "b"), None);
+        cache.insert("c".into(), "3".into(), 0);
+        assert!(get(&cache, "c").is_some());
+    }
+
+    #[test]
+    fn sweep_reclaims_due_items() {
+        let clock = MockClock::new();
+        let cache = cache(&clock);
+        for key in ["a", "b", "c"] {
+            cache.insert_with_ttl(key.into(), "1".into(), 1, 0);
+        }
+        // stored again with a later deadline, the sweep finds it alive and schedules it again
+        cache.insert_with_ttl("c".into(), "2".into(), 10, 0);
+        cache.insert_with_idle("d".into(), "3".into(), 5, 0, 0);
+        cache.insert("e".into(), "4".into(), 0);
+
+        clock.advance(Duration::from_secs(3));
+        assert!(get(&cache, "d").is_some());
+        cache.sweep();
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.stats().reclaimed, 2);
+
+        clock.advance(Duration::from_secs(8));
+        cache.sweep();
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.stats().reclaimed, 4);
+        assert!(get(&cache, "e").is_some());
     }
 }
@@ -0,0 +1,152 @@
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+
+/// Why an item left the cache, see [`crate::CacheBuilder::removal_listener`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RemovalCause {
+    /// Its ttl has passed.
+    Expired,
+    /// Its value was replaced by a store or an update, e.g. an append. The key stays.
+    Replaced,
+    /// It was evicted to free memory.
+    Evicted,
+    /// It was removed by a delete, a clear or a flush.
+    Explicit,
+}
+
+pub(crate) type Listener<K, V> = Box<dyn Fn(&K, &V, RemovalCause) + Send>;
+
+/// How many items may wait for the listeners, later ones are dropped until they catch up.
+const QUEUE_LEN: usize = 64 * 1024;
+
+/// Hands the items leaving the cache to the listeners, which run on a thread of their own so a
+/// slow listener does not stall the writes to the cache.
+pub(crate) struct Notifier<K, V> {
+    sender: SyncSender<(K, V, RemovalCause)>,
+    /// Clones a value, listeners can only be added for cloneable values.
+    copy: fn(&V) -> V,
+}
+
+impl<K: Clone + Send + 'static, V: Send + 'static> Notifier<K, V> {
+    pub(crate) fn spawn(listeners: Vec<Listener<K, V>>, copy: fn(&V) -> V) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<(K, V, RemovalCause)>(QUEUE_LEN);
+        thread::Builder::new()
+            .name("kv-cache-listener".to_string())
+            .spawn(move || {
+                // ends once the last clone of the cache, and so the sender, is dropped
+                for (key, value, cause) in receiver {
+                    for listener in &listeners {
+                        listener(&key, &value, cause);
+                    }
+                }
+            })
+            .expect("can not spawn the listener thread");
+        Notifier { sender, copy }
+    }
+
+    /// Queues an item for the listeners, never blocks. Returns false if the item was dropped as
+    /// the queue is full.
+    pub(crate) fn notify(&self, key: &K, value: &V, cause: RemovalCause) -> bool {
+        match self
+            .sender
+            .try_send((key.clone(), (self.copy)(value), cause))
+        {
+            Err(TrySendError::Full(_)) => false,
+            // disconnected only if a listener panicked and took the thread down
+            Ok(()) | Err(TrySendError::Disconnected(_)) => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cache, CacheBuilder, MockClock};
+    use std::sync::mpsc::Receiver;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime};
+
+    type Removal = (String, String, RemovalCause);
+
+    /// A cache whose removal listener sends the removed items back.
+    fn listened(
+        clock: &MockClock,
+        builder: CacheBuilder<String, String>,
+    ) -> (Cache<String, String>, Receiver<Removal>) {
+        let (sender, receiver) = mpsc::channel();
+        let cache = builder
+            .clock(clock.clone())
+            .without_vacuum_thread()
+            .removal_listener(move |key: &String, value: &String, cause| {
+                let _ = sender.send((key.clone(), value.clone(), cause));
+            })
+            .build();
+        (cache, receiver)
+    }
+
+    fn next(removals: &Receiver<Removal>) -> Removal {
+        removals.recv_timeout(Duration::from_secs(5)).unwrap()
+    }
+
+    fn removal(key: &str, value: &str, cause: RemovalCause) -> Removal {
+        (key.to_string(), value.to_string(), cause)
+    }
+
+    #[test]
+    fn hands_each_cause_to_the_listener() {
+        let clock = MockClock::new();
+        let (cache, removals) = listened(&clock, Cache::builder());
+
+        cache.insert("a".into(), "1".into(), 0);
+        cache.remove("a");
+        assert_eq!(next(&removals), removal("a", "1", RemovalCause::Explicit));
+
+        cache.insert("b".into(), "1".into(), 0);
+        cache.insert("b".into(), "2".into(), 0);
+        assert_eq!(next(&removals), removal("b", "1", RemovalCause::Replaced));
+
+        cache.insert_with_ttl("c".into(), "1".into(), 1, 0);
+        clock.advance(Duration::from_secs(2));
+        cache.sweep();
+        assert_eq!(next(&removals), removal("c", "1", RemovalCause::Expired));
+
+        // flushes count as explicit removals
+        cache.clear();
+        assert_eq!(next(&removals), removal("b", "2", RemovalCause::Explicit));
+        cache.insert("d".into(), "1".into(), 0);
+        cache.invalidate_before(SystemTime::now());
+        clock.advance(Duration::from_secs(1));
+        cache.sweep();
+        assert_eq!(next(&removals), removal("d", "1", RemovalCause::Explicit));
+        assert!(removals.try_recv().is_err());
+    }
+
+    #[test]
+    fn hands_evicted_items_to_the_listener() {
+        let clock = MockClock::new();
+        let (cache, removals) = listened(&clock, Cache::builder().max_bytes(1));
+
+        cache.insert("a".into(), "1".into(), 0);
+        assert_eq!(next(&removals), removal("a", "1", RemovalCause::Evicted));
+    }
+
+    #[test]
+    fn drops_items_rather_than_wait_for_a_full_queue() {
+        let gate = Arc::new(Mutex::new(()));
+        let listener = gate.clone();
+        let cache: Cache<String, String> = Cache::builder()
+            .without_vacuum_thread()
+            .removal_listener(move |_, _, _| drop(listener.lock().unwrap()))
+            .build();
+        let closed = gate.lock().unwrap();
+
+        // the listener waits at the gate with the first item, the queue takes the next ones
+        for i in 0..QUEUE_LEN + 10 {
+            cache.insert(i.to_string(), String::new(), 0);
+            cache.remove(&i.to_string());
+        }
+        let dropped = cache.stats().dropped_notifications;
+        assert!((9..=10).contains(&dropped), "dropped {}", dropped);
+        drop(closed);
+    }
+}
@@ -0,0 +1,328 @@
+//! Loading missing keys from a slow backend. However many tasks ask for a missing key, only one
+//! loader runs for it at a time, the other tasks wait for its result.
+use crate::{ByteSize, Cache, Compute, Item};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Error of a loader, shared by all the calls which waited for it.
+#[derive(Clone, Debug)]
+pub struct LoadError(Arc<dyn Error + Send + Sync>);
+
+impl LoadError {
+    /// The error returned by the loader.
+    pub fn inner(&self) -> &(dyn Error + Send + Sync + 'static) {
+        &*self.0
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.0.source()
+    }
+}
+
+/// What a loader came back with, `None` if the backend has no value for the key.
+type Outcome<V> = Result<Option<Item<V>>, LoadError>;
+
+/// The loads of the keys missing from a cache.
+pub(crate) type Loads<K, V> = Mutex<HashMap<K, Load<V>>>;
+
+pub(crate) enum Load<V> {
+    /// A loader is running, its outcome is sent once it is done.
+    InFlight(watch::Receiver<Option<Outcome<V>>>),
+    /// The loader found no value, it is not run again until this time of the cache clock.
+    Missing(Duration),
+}
+
+/// What a call of [`Cache::get_or_load`] does about a missing key.
+enum Role<V> {
+    /// Nothing, the key was stored meanwhile or a loader found it missing not long ago.
+    Done(Outcome<V>),
+    /// Waits for the outcome of the running loader.
+    Wait(watch::Receiver<Option<Outcome<V>>>),
+    /// Runs the loader and sends its outcome to the waiters.
+    Lead(watch::Sender<Option<Outcome<V>>>),
+}
+
+/// The running loader of a key. Takes the key out of the loads when dropped, so the waiters load
+/// again if the loader is cancelled.
+struct Flight<'a, K: Eq + Hash, V> {
+    loads: &'a Loads<K, V>,
+    key: Option<K>,
+    sender: watch::Sender<Option<Outcome<V>>>,
+}
+
+impl<K: Eq + Hash, V> Flight<'_, K, V> {
+    /// Hands the outcome to the waiters, the key stays in the loads if it is missing and the
+    /// cache has a negative ttl.
+    fn finish(mut self, outcome: Outcome<V>, missing_until: Option<Duration>) {
+        let key = self.key.take().unwrap();
+        let mut loads = self.loads.lock().unwrap();
+        match missing_until {
+            Some(until) if matches!(outcome, Ok(None)) => {
+                loads.insert(key, Load::Missing(until));
+            }
+            _ => {
+                loads.remove(&key);
+            }
+        }
+        drop(loads);
+        // fails if nobody waits
+        let _ = self.sender.send(Some(outcome));
+    }
+}
+
+impl<K: Eq + Hash, V> Drop for Flight<'_, K, V> {
+    fn drop(&mut self) {
+        if let Some(key) = &self.key {
+            self.loads.lock().unwrap().remove(key);
+        }
+    }
+}
+
+impl<K, V> Cache<K, V>
+where
+    K: Eq + Hash + Clone + ByteSize + Send + Sync + 'static,
+    V: ByteSize + Clone + Send + Sync + 'static,
+{
+    /// Gets a key, or loads it with `loader` if it is missing (or has expired). The loaded value
+    /// is stored with the default ttl and no flag.
+    ///
+    /// Concurrent calls for a missing key wait for the loader of the first one instead of running
+    /// their own, and get its value or error. A loader finding no value returns `Ok(None)`, which
+    /// is remembered for the [`crate::CacheBuilder::negative_ttl`] if one is set. Errors are not
+    /// remembered, the next call loads again.
+    pub async fn get_or_load<F, Fut, E>(
+        &self,
+        key: K,
+        loader: F,
+    ) -> Result<Option<Item<V>>, LoadError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<V>, E>>,
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        let mut loader = Some(loader);
+        loop {
+            if let Some(item) = self.get(&key) {
+                return Ok(Some(item));
+            }
+            let mut receiver = match self.role(&key) {
+                Role::Done(outcome) => return outcome,
+                Role::Wait(receiver) => receiver,
+                Role::Lead(sender) => {
+                    let flight = Flight {
+                        loads: &self.loads,
+                        key: Some(key.clone()),
+                        sender,
+                    };
+                    // a call leads at most once as it returns with the outcome, it leads in a
+                    // later round if the loader it waited for was cancelled
+                    let loader = loader.take().expect("the loader runs once");
+                    return self.load(key, flight, loader).await;
+                }
+            };
+            self.counters.coalesced.fetch_add(1, Ordering::Relaxed);
+            loop {
+                if let Some(outcome) = &*receiver.borrow() {
+                    return outcome.clone();
+                }
+                if receiver.changed().await.is_err() {
+                    // the loader was cancelled, try again
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Decides whether a call finding a key missing waits for a loader or runs its own.
+    fn role(&self, key: &K) -> Role<V> {
+        let mut loads = self.loads.lock().unwrap();
+        match loads.get(key) {
+            Some(Load::Missing(until)) if *until > self.now() => Role::Done(Ok(None)),
+            Some(Load::InFlight(receiver)) => Role::Wait(receiver.clone()),
+            _ => {
+                // a loader may have stored the key since it was looked up
+                if let Some(item) = self.peek(key) {
+                    return Role::Done(Ok(Some(item)));
+                }
+                let (sender, receiver) = watch::channel(None);
+                loads.insert(key.clone(), Load::InFlight(receiver));
+                Role::Lead(sender)
+            }
+        }
+    }
+
+    async fn load<F, Fut, E>(&self, key: K, flight: Flight<'_, K, V>, loader: F) -> Outcome<V>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<V>, E>>,
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        self.counters.loads.fetch_add(1, Ordering::Relaxed);
+        let outcome = match loader().await {
+            Ok(Some(value)) => Ok(self.compute(key, |_| Compute::Set { value, flag: 0 })),
+            Ok(None) => Ok(None),
+            Err(e) => {
+                self.counters.load_errors.fetch_add(1, Ordering::Relaxed);
+                Err(LoadError(Arc::from(e.into())))
+            }
+        };
+        let missing_until = self.negative_ttl.map(|ttl| self.now() + ttl);
+        flight.finish(outcome.clone(), missing_until);
+        outcome
+    }
+
+    /// Gets a live item without counting a hit or a miss.
+    fn peek(&self, key: &K) -> Option<Item<V>> {
+        self.map
+            .get(key)
+            .filter(|r| !self.is_expired(r))
+            .map(|r| Item::from(&*r))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockClock;
+    use std::future;
+    use std::sync::atomic::AtomicUsize;
+
+    fn cache(clock: &MockClock) -> Cache<String, String> {
+        Cache::builder()
+            .clock(clock.clone())
+            .without_vacuum_thread()
+            .build()
+    }
+
+    /// A loader which takes a while to find `value`, counting its runs.
+    async fn slow_load(
+        runs: &AtomicUsize,
+        value: Option<&str>,
+    ) -> Result<Option<String>, &'static str> {
+        runs.fetch_add(1, Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        Ok(value.map(String::from))
+    }
+
+    fn value(outcome: Outcome<String>) -> Option<String> {
+        outcome.unwrap().map(Item::into_value)
+    }
+
+    fn error(outcome: Outcome<String>) -> String {
+        match outcome {
+            Err(e) => e.to_string(),
+            Ok(_) => panic!("the load did not fail"),
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_calls_share_one_load() {
+        let cache = cache(&MockClock::new());
+        let runs = AtomicUsize::new(0);
+        let load = || cache.get_or_load("a".to_string(), || slow_load(&runs, Some("1")));
+        let (first, second, third) = tokio::join!(load(), load(), load());
+
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+        for outcome in [first, second, third] {
+            assert_eq!(value(outcome).as_deref(), Some("1"));
+        }
+        let stats = cache.stats();
+        assert_eq!((stats.loads, stats.coalesced), (1, 2));
+        // the loaded value is stored
+        assert_eq!(value(load().await).as_deref(), Some("1"));
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn a_loader_error_reaches_every_waiter() {
+        let cache = cache(&MockClock::new());
+        let runs = AtomicUsize::new(0);
+        let fail = || async {
+            runs.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            Err::<Option<String>, _>("backend down")
+        };
+        let (first, second) = tokio::join!(
+            cache.get_or_load("a".to_string(), fail),
+            cache.get_or_load("a".to_string(), fail)
+        );
+
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+        assert_eq!(error(first), "backend down");
+        assert_eq!(error(second), "backend down");
+        assert_eq!(cache.stats().load_errors, 1);
+        // errors are not remembered
+        let loaded = cache.get_or_load("a".to_string(), || slow_load(&runs, Some("1")));
+        assert_eq!(value(loaded.await).as_deref(), Some("1"));
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn remembers_a_missing_key_for_the_negative_ttl() {
+        let clock = MockClock::new();
+        let cache: Cache<String, String> = Cache::builder()
+            .clock(clock.clone())
+            .default_ttl(Duration::from_secs(100))
+            .negative_ttl(Duration::from_secs(10))
+            .without_vacuum_thread()
+            .build();
+        let runs = AtomicUsize::new(0);
+        let load = || cache.get_or_load("a".to_string(), || slow_load(&runs, None));
+
+        assert_eq!(value(load().await), None);
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(value(load().await), None);
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+        // the negative ttl is shorter than the ttl of the items
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(value(load().await), None);
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn does_not_remember_a_missing_key_without_a_negative_ttl() {
+        let cache = cache(&MockClock::new());
+        let runs = AtomicUsize::new(0);
+        let load = || cache.get_or_load("a".to_string(), || slow_load(&runs, None));
+
+        assert_eq!(value(load().await), None);
+        assert_eq!(value(load().await), None);
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn a_waiter_takes_over_from_a_cancelled_loader() {
+        let cache = cache(&MockClock::new());
+        let runs = AtomicUsize::new(0);
+        let stuck = || async {
+            runs.fetch_add(1, Ordering::Relaxed);
+            future::pending::<Result<Option<String>, &str>>().await
+        };
+        let leader = tokio::time::timeout(
+            Duration::from_millis(10),
+            cache.get_or_load("a".to_string(), stuck),
+        );
+        let waiter = cache.get_or_load("a".to_string(), || slow_load(&runs, Some("1")));
+        let (leader, waiter) = tokio::join!(leader, waiter);
+
+        assert!(leader.is_err());
+        assert_eq!(value(waiter).as_deref(), Some("1"));
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+        assert!(cache.loads.lock().unwrap().is_empty());
+    }
+}
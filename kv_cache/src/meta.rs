@@ -0,0 +1,382 @@
+//! Operations behind the memcached meta protocol, including stale items and recache wins which
+//! protect slow backends from a thundering herd when a hot item expires or is invalidated.
+use crate::{concat, micros, ByteSize, Cache, RemovalCause, Value};
+use bytes::Bytes;
+use dashmap::mapref::entry::Entry;
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::mem;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// Options of [`Cache::meta_get`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MetaGetOptions {
+    /// Updates the ttl of the item.
+    pub touch_ttl: Option<u32>,
+    /// Creates an empty item with this ttl on a miss, the caller then wins the right to fill it.
+    pub vivify_ttl: Option<u32>,
+    /// The caller wins the right to recache the item if its remaining ttl is below this.
+    pub recache_ttl: Option<u32>,
+    /// Does not mark the item as accessed.
+    pub no_bump: bool,
+}
+
+/// How [`Cache::meta_set`] stores the data.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MetaSetMode {
+    #[default]
+    Set,
+    Add,
+    Replace,
+    Append,
+    Prepend,
+}
+
+/// Options of [`Cache::meta_set`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MetaSetOptions {
+    pub mode: MetaSetMode,
+    pub ttl: u32,
+    pub flag: u32,
+    /// Only stores if the cas of the item equals this one.
+    pub compare_cas: Option<u64>,
+    /// Uses this as the new cas instead of generating one.
+    pub new_cas: Option<u64>,
+    /// Together with `compare_cas`, stores the data as stale instead of failing if the given cas
+    /// is older than the one of the item.
+    pub invalidate: bool,
+    /// In append or prepend mode, creates the item with this ttl if it is missing.
+    pub vivify_ttl: Option<u32>,
+    /// Expires the stored item once it has not been accessed for this many seconds, on top of
+    /// its ttl. The idle timeout of the cache applies if not set or 0.
+    pub idle: Option<u32>,
+}
+
+/// Options of [`Cache::meta_delete`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MetaDeleteOptions {
+    /// Only deletes if the cas of the item equals this one.
+    pub compare_cas: Option<u64>,
+    /// Uses this as the new cas instead of generating one, when the item is kept.
+    pub new_cas: Option<u64>,
+    /// Marks the item as stale instead of removing it, so the next fetch wins the recache.
+    pub invalidate: bool,
+    /// Updates the ttl of an invalidated item.
+    pub ttl: Option<u32>,
+    /// Removes the value but keeps the item in place.
+    pub keep_item: bool,
+}
+
+/// Options of [`Cache::meta_arithmetic`].
+#[derive(Clone, Copy, Debug)]
+pub struct MetaArithmeticOptions {
+    /// Increments if true, decrements otherwise.
+    pub incr: bool,
+    pub delta: u64,
+    /// Only updates if the cas of the item equals this one.
+    pub compare_cas: Option<u64>,
+    /// Uses this as the new cas instead of generating one.
+    pub new_cas: Option<u64>,
+    /// Creates the item with this ttl and the `initial` value if it is missing.
+    pub vivify_ttl: Option<u32>,
+    pub initial: u64,
+    /// Updates the ttl of the item.
+    pub touch_ttl: Option<u32>,
+}
+
+impl Default for MetaArithmeticOptions {
+    fn default() -> Self {
+        MetaArithmeticOptions {
+            incr: true,
+            delta: 1,
+            compare_cas: None,
+            new_cas: None,
+            vivify_ttl: None,
+            initial: 0,
+            touch_ttl: None,
+        }
+    }
+}
+
+/// Why a meta operation did not happen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetaError {
+    /// The item was not stored because of the mode, e.g. add on an existing key.
+    NotStored,
+    /// The cas of the item does not match the given one.
+    Exists,
+    /// The key does not exist (or has expired).
+    NotFound,
+    /// The value is not a decimal representation of a 64-bit unsigned integer.
+    NonNumeric,
+}
+
+/// A copy of an item and its metadata, as returned by the meta operations.
+#[derive(Clone, Debug)]
+pub struct MetaItem<V> {
+    pub value: V,
+    pub flag: u32,
+    pub cas: u64,
+    /// Remaining ttl in seconds, None if the item never expires.
+    pub ttl: Option<u64>,
+    /// Seconds since the item was last fetched.
+    pub last_access: u64,
+    /// Whether the item had been fetched before.
+    pub fetched: bool,
+    /// Whether the item was marked as stale by an invalidation.
+    pub stale: bool,
+    /// `Some(true)` if the caller won the right to recache the item, `Some(false)` if somebody
+    /// else already did.
+    pub win: Option<bool>,
+}
+
+impl<V: Clone> MetaItem<V> {
+    fn new(v: &Value<V>, win: Option<bool>, now: Duration) -> Self {
+        let accessed = v.accessed.load(Ordering::Relaxed);
+        MetaItem {
+            value: v.value.clone(),
+            flag: v.flag,
+            cas: v.cas,
+            ttl: v.remaining_ttl(now),
+            last_access: micros(now).saturating_sub(accessed) / 1_000_000,
+            fetched: v.fetched.load(Ordering::Relaxed),
+            stale: v.stale,
+            win,
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone + ByteSize + Send + Sync + 'static> Cache<K, Bytes> {
+    /// Fetches an item, handing out the right to recache it to a single caller when it is stale,
+    /// about to expire or missing.
+    pub fn meta_get(&self, key: K, opts: MetaGetOptions) -> Option<MetaItem<Bytes>> {
+        let item = match self.map.entry(key) {
+            Entry::Occupied(mut o) if !self.is_expired(o.get()) => {
+                self.counters.record_lookup(true);
+                let v = o.get_mut();
+                // the metadata is returned as it was before this fetch
+                let mut item = MetaItem::new(v, None, self.now());
+                if let Some(ttl) = opts.touch_ttl {
+                    v.set_ttl(ttl, self.now());
+                    item.ttl = v.remaining_ttl(self.now());
+                }
+                let recache = match (opts.recache_ttl, v.remaining_ttl(self.now())) {
+                    (Some(recache_ttl), Some(ttl)) => ttl < recache_ttl as u64,
+                    _ => false,
+                };
+                if v.stale || recache || v.win_sent {
+                    item.win = Some(!v.win_sent);
+                    v.win_sent = true;
+                }
+                if !opts.no_bump {
+                    v.fetched.store(true, Ordering::Relaxed);
+                    v.accessed.store(micros(self.now()), Ordering::Relaxed);
+                    self.record_hit(o.key());
+                }
+                if opts.touch_ttl.is_some() {
+                    self.schedule_expiry(o.key(), o.get());
+                    self.journal_touch(o.key(), o.get());
+                }
+                item
+            }
+            entry => {
+                self.counters.record_lookup(false);
+                let ttl = opts.vivify_ttl?;
+                let mut value =
+                    Value::new_with_ttl(Bytes::new(), ttl, 0, self.next_cas(), self.now());
+                value.win_sent = true;
+                let item = MetaItem::new(&value, Some(true), self.now());
+                self.put_entry(entry, value);
+                item
+            }
+        };
+        self.evict();
+        Some(item)
+    }
+
+    /// Stores data according to the mode and cas options. Returns the cas of the stored item.
+    pub fn meta_set(&self, key: K, data: Bytes, opts: MetaSetOptions) -> Result<u64, MetaError> {
+        let cas = opts.new_cas.unwrap_or_else(|| self.next_cas());
+        match self.map.entry(key) {
+            Entry::Occupied(mut o) if !self.is_expired(o.get()) => {
+                let mut stale = false;
+                if let Some(compare_cas) = opts.compare_cas {
+                    let current = o.get().cas;
+                    if compare_cas != current {
+                        if opts.invalidate && compare_cas < current {
+                            stale = true;
+                        } else {
+                            return Err(MetaError::Exists);
+                        }
+                    }
+                }
+                match opts.mode {
+                    MetaSetMode::Add => return Err(MetaError::NotStored),
+                    MetaSetMode::Append | MetaSetMode::Prepend => {
+                        self.counters.add_bytes(data.len() as i64);
+                        let v = o.get_mut();
+                        let value = if opts.mode == MetaSetMode::Append {
+                            concat(&v.value, &data)
+                        } else {
+                            concat(&data, &v.value)
+                        };
+                        let old = mem::replace(&mut v.value, value);
+                        let previous_cas = mem::replace(&mut v.cas, cas);
+                        v.updated = self.now();
+                        v.stale = stale;
+                        v.win_sent = false;
+                        self.counters.record_store(true);
+                        self.notify(o.key(), &old, RemovalCause::Replaced);
+                        let append = opts.mode == MetaSetMode::Append;
+                        self.journal_append(o.key(), o.get(), previous_cas, &data, append);
+                        self.record_use(o.key());
+                    }
+                    MetaSetMode::Set | MetaSetMode::Replace => {
+                        let mut value =
+                            Value::new_with_ttl(data, opts.ttl, opts.flag, cas, self.now());
+                        if let Some(idle) = opts.idle {
+                            value.set_idle(idle);
+                        }
+                        value.stale = stale;
+                        self.replace_entry(&mut o, value);
+                    }
+                }
+            }
+            entry => {
+                if opts.compare_cas.is_some() {
+                    return Err(MetaError::NotFound);
+                }
+                let ttl = match opts.mode {
+                    MetaSetMode::Replace => return Err(MetaError::NotStored),
+                    MetaSetMode::Append | MetaSetMode::Prepend => {
+                        opts.vivify_ttl.ok_or(MetaError::NotStored)?
+                    }
+                    MetaSetMode::Set | MetaSetMode::Add => opts.ttl,
+                };
+                let mut value = Value::new_with_ttl(data, ttl, opts.flag, cas, self.now());
+                if let Some(idle) = opts.idle {
+                    value.set_idle(idle);
+                }
+                self.put_entry(entry, value);
+            }
+        }
+        self.evict();
+        Ok(cas)
+    }
+
+    /// Deletes an item, or marks it as stale when invalidating.
+    pub fn meta_delete(&self, key: K, opts: MetaDeleteOptions) -> Result<(), MetaError> {
+        match self.map.entry(key) {
+            Entry::Occupied(mut o) if !self.is_expired(o.get()) => {
+                if opts.compare_cas.is_some_and(|cas| cas != o.get().cas) {
+                    return Err(MetaError::Exists);
+                }
+                if opts.invalidate || opts.keep_item {
+                    let cas = opts.new_cas.unwrap_or_else(|| self.next_cas());
+                    let v = o.get_mut();
+                    let emptied = if opts.invalidate {
+                        v.stale = true;
+                        v.win_sent = false;
+                        if let Some(ttl) = opts.ttl {
+                            v.set_ttl(ttl, self.now());
+                        }
+                        None
+                    } else {
+                        self.counters.add_bytes(-(v.value.len() as i64));
+                        Some(mem::take(&mut v.value))
+                    };
+                    v.cas = cas;
+                    if let Some(old) = emptied {
+                        self.notify(o.key(), &old, RemovalCause::Replaced);
+                        self.journal_set(o.key(), o.get());
+                    }
+                    if opts.invalidate && opts.ttl.is_some() {
+                        self.schedule_expiry(o.key(), o.get());
+                        self.journal_touch(o.key(), o.get());
+                    }
+                } else {
+                    self.forget(o.key());
+                    self.journal_delete(o.key());
+                    let (k, v) = o.remove_entry();
+                    self.counters.remove_item(self.item_size(&k, &v));
+                    self.notify(&k, &v.value, RemovalCause::Explicit);
+                }
+                Ok(())
+            }
+            _ => Err(MetaError::NotFound),
+        }
+    }
+
+    /// Increments or decrements the decimal number stored at a key. Returns the updated item.
+    pub fn meta_arithmetic(
+        &self,
+        key: K,
+        opts: MetaArithmeticOptions,
+    ) -> Result<MetaItem<Bytes>, MetaError> {
+        let item = match self.map.entry(key) {
+            Entry::Occupied(mut o) if !self.is_expired(o.get()) => {
+                if opts.compare_cas.is_some_and(|cas| cas != o.get().cas) {
+                    return Err(MetaError::Exists);
+                }
+                let cas = opts.new_cas.unwrap_or_else(|| self.next_cas());
+                let v = o.get_mut();
+                let n = std::str::from_utf8(&v.value)
+                    .ok()
+                    .and_then(|s| s.parse::<u64>().ok())
+                    .ok_or(MetaError::NonNumeric)?;
+                let n = if opts.incr {
+                    n.wrapping_add(opts.delta)
+                } else {
+                    n.saturating_sub(opts.delta)
+                };
+                let old = mem::replace(&mut v.value, Bytes::from(n.to_string()));
+                self.counters
+                    .add_bytes(v.value.len() as i64 - old.len() as i64);
+                self.counters.record_store(true);
+                v.cas = cas;
+                v.updated = self.now();
+                v.fetched.store(true, Ordering::Relaxed);
+                if let Some(ttl) = opts.touch_ttl {
+                    v.set_ttl(ttl, self.now());
+                }
+                let item = MetaItem::new(v, None, self.now());
+                if opts.touch_ttl.is_some() {
+                    self.schedule_expiry(o.key(), o.get());
+                }
+                self.notify(o.key(), &old, RemovalCause::Replaced);
+                self.journal_set(o.key(), o.get());
+                self.record_use(o.key());
+                item
+            }
+            entry => {
+                let ttl = opts.vivify_ttl.ok_or(MetaError::NotFound)?;
+                let cas = opts.new_cas.unwrap_or_else(|| self.next_cas());
+                let value = Value::new_with_ttl(
+                    Bytes::from(opts.initial.to_string()),
+                    ttl,
+                    0,
+                    cas,
+                    self.now(),
+                );
+                let item = MetaItem::new(&value, None, self.now());
+                self.put_entry(entry, value);
+                item
+            }
+        };
+        self.evict();
+        Ok(item)
+    }
+
+    /// Returns an item and its metadata without touching it or counting a hit or miss.
+    pub fn meta_debug<Q>(&self, key: &Q) -> Option<MetaItem<Bytes>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map
+            .get(key)
+            .filter(|v| !self.is_expired(v))
+            .map(|v| MetaItem::new(&v, None, self.now()))
+    }
+}
@@ -0,0 +1,467 @@
+//! Snapshots of the items of a cache, so a restarted process does not start with an empty cache.
+//!
+//! A snapshot is a header (magic, format version and the system time it was written at), the
+//! items one after the other, and a trailer with the number of items and a CRC-32 of everything
+//! before it. Numbers are big endian. Ttls are stored as remaining time, the time the snapshot
+//! spent on disk is taken off when it is read.
+use crate::{ByteSize, Cache, Value};
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAGIC: &[u8; 8] = b"MCKVSNAP";
+const VERSION: u32 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 4 + 8;
+/// The end tag, the number of items and the checksum.
+const TRAILER_LEN: usize = 1 + 8 + 4;
+const TAG_ITEM: u8 = 1;
+const TAG_END: u8 = 0;
+/// How much of a snapshot is read at once to compute its checksum.
+const CHUNK_LEN: usize = 64 * 1024;
+/// Ttl of an item which never expires.
+const NO_TTL: u64 = u64::MAX;
+
+/// Error of [`Cache::read_snapshot`].
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    /// Not a snapshot, or one which was cut short.
+    Corrupt(&'static str),
+    /// Written in a format version this one can not read.
+    UnsupportedVersion(u32),
+    /// The content does not match its checksum.
+    Checksum,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "can not read the snapshot: {}", e),
+            SnapshotError::Corrupt(what) => write!(f, "corrupt snapshot: {}", what),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            SnapshotError::Checksum => write!(f, "snapshot checksum mismatch"),
+        }
+    }
+}
+
+impl Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SnapshotError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+/// An item as it is written to a snapshot, copied out so no lock is held while writing.
+struct Entry<K, V> {
+    key: K,
+    value: V,
+    flag: u32,
+    cas: u64,
+    ttl: Option<Duration>,
+    idle: Option<Duration>,
+}
+
+impl<K, V> Cache<K, V>
+where
+    K: Eq + Hash + Clone + ByteSize + Send + Sync + 'static,
+    V: ByteSize + Send + Sync + 'static,
+{
+    /// Writes the live items with their flags, cas and remaining ttls. Returns the number of items
+    /// written.
+    ///
+    /// The items of a shard are copied out under its read lock and written after the lock is
+    /// released, so a slow writer does not block the cache. Items stored meanwhile may or may not
+    /// be in the snapshot.
+    pub fn write_snapshot<W: Write>(&self, writer: W) -> io::Result<u64>
+    where
+        K: AsRef<[u8]>,
+        V: Clone + AsRef<[u8]>,
+    {
+        let mut out = Checksummed::new(writer);
+        let written_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_be_bytes())?;
+        out.write_all(&(written_at.as_millis() as u64).to_be_bytes())?;
+
+        let mut items: u64 = 0;
+        for shard in self.map.shards() {
+            let now = self.now();
+            let entries: Vec<Entry<K, V>> = shard
+                .read()
+                .iter()
+                .filter(|(_, v)| !self.is_expired(v.get()))
+                .map(|(k, v)| {
+                    let v = v.get();
+                    Entry {
+                        key: k.clone(),
+                        value: v.value.clone(),
+                        flag: v.flag,
+                        cas: v.cas,
+                        ttl: v.timestamp.map(|t| t.saturating_sub(now)),
+                        idle: v.idle,
+                    }
+                })
+                .collect();
+            for entry in entries {
+                write_entry(&mut out, &entry)?;
+                items += 1;
+            }
+        }
+
+        out.write_all(&[TAG_END])?;
+        out.write_all(&items.to_be_bytes())?;
+        let checksum = out.checksum();
+        let mut writer = out.into_inner();
+        writer.write_all(&checksum.to_be_bytes())?;
+        writer.flush()?;
+        Ok(items)
+    }
+
+    /// Stores the items of a snapshot over the ones in the cache, skipping the items which
+    /// expired while the snapshot was on disk. Returns the number of items stored.
+    ///
+    /// The snapshot is read twice: once to check it against its checksum, so nothing is stored
+    /// from a damaged snapshot, then to store its items one at a time, so it is never all in
+    /// memory.
+    pub fn read_snapshot<R: Read + Seek>(&self, mut reader: R) -> Result<u64, SnapshotError>
+    where
+        K: From<Vec<u8>>,
+        V: From<Vec<u8>>,
+    {
+        let len = reader.seek(SeekFrom::End(0))?;
+        if len < (HEADER_LEN + TRAILER_LEN) as u64 {
+            return Err(SnapshotError::Corrupt("too short"));
+        }
+        reader.seek(SeekFrom::Start(0))?;
+        // the header comes first, a later version may not end with the same checksum
+        let mut header = [0; HEADER_LEN];
+        reader.read_exact(&mut header)?;
+        let mut input = Input(&header);
+        if input.take(MAGIC.len())? != MAGIC {
+            return Err(SnapshotError::Corrupt("not a snapshot"));
+        }
+        let version = input.u32()?;
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let written_at = UNIX_EPOCH + Duration::from_millis(input.u64()?);
+        let downtime = SystemTime::now()
+            .duration_since(written_at)
+            .unwrap_or_default();
+
+        let items_len = len - 4 - HEADER_LEN as u64;
+        let mut crc = crc32(CRC32_INIT, &header);
+        let mut chunk = vec![0; CHUNK_LEN];
+        let mut left = items_len;
+        while left > 0 {
+            let chunk = &mut chunk[..left.min(CHUNK_LEN as u64) as usize];
+            reader.read_exact(chunk)?;
+            crc = crc32(crc, chunk);
+            left -= chunk.len() as u64;
+        }
+        let mut checksum = [0; 4];
+        reader.read_exact(&mut checksum)?;
+        if crc ^ CRC32_INIT != u32::from_be_bytes(checksum) {
+            return Err(SnapshotError::Checksum);
+        }
+
+        reader.seek(SeekFrom::Start(HEADER_LEN as u64))?;
+        let mut input = ReadInput(reader.take(items_len));
+        let mut items = 0;
+        let mut stored = 0;
+        loop {
+            let entry = match input.u8()? {
+                TAG_ITEM => read_entry(&mut input)?,
+                TAG_END => break,
+                _ => return Err(SnapshotError::Corrupt("unknown record")),
+            };
+            items += 1;
+            let ttl = match entry.ttl {
+                Some(ttl) if ttl <= downtime => continue,
+                ttl => ttl.map(|ttl| ttl - downtime),
+            };
+            let mut value =
+                Value::new(V::from(entry.value), ttl, entry.flag, entry.cas, self.now());
+            value.idle = entry.idle;
+            // later writes must not reuse the cas of a restored item
+            self.cas_counter.fetch_max(entry.cas, Ordering::Relaxed);
+            self.store(K::from(entry.key), value);
+            stored += 1;
+        }
+        if input.u64()? != items || input.0.limit() != 0 {
+            return Err(SnapshotError::Corrupt("item count mismatch"));
+        }
+        Ok(stored)
+    }
+}
+
+fn write_entry<W, K, V>(out: &mut W, entry: &Entry<K, V>) -> io::Result<()>
+where
+    W: Write,
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+{
+    let ttl = entry.ttl.map_or(NO_TTL, |ttl| ttl.as_millis() as u64);
+    let idle = entry.idle.map_or(0, |idle| idle.as_millis() as u64);
+    let key = entry.key.as_ref();
+    let value = entry.value.as_ref();
+    out.write_all(&[TAG_ITEM])?;
+    out.write_all(&entry.flag.to_be_bytes())?;
+    out.write_all(&entry.cas.to_be_bytes())?;
+    out.write_all(&ttl.to_be_bytes())?;
+    out.write_all(&idle.to_be_bytes())?;
+    out.write_all(&(key.len() as u32).to_be_bytes())?;
+    out.write_all(key)?;
+    out.write_all(&(value.len() as u32).to_be_bytes())?;
+    out.write_all(value)
+}
+
+fn read_entry<R: Read>(input: &mut ReadInput<R>) -> Result<Entry<Vec<u8>, Vec<u8>>, SnapshotError> {
+    let flag = input.u32()?;
+    let cas = input.u64()?;
+    let ttl = input.u64()?;
+    let idle = input.u64()?;
+    let key = input.bytes()?;
+    let value = input.bytes()?;
+    Ok(Entry {
+        key,
+        value,
+        flag,
+        cas,
+        ttl: (ttl != NO_TTL).then(|| Duration::from_millis(ttl)),
+        idle: (idle > 0).then(|| Duration::from_millis(idle)),
+    })
+}
+
+/// The unread part of a journal record, or of the header of a snapshot.
+pub(crate) struct Input<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Input<'a> {
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.0.len() < len {
+            return Err(SnapshotError::Corrupt("cut short"));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Bytes preceded by their length.
+    pub(crate) fn bytes(&mut self) -> Result<&'a [u8], SnapshotError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+/// The unread items of a snapshot, read from the snapshot as they are decoded.
+struct ReadInput<R>(io::Take<R>);
+
+impl<R: Read> ReadInput<R> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        let mut buf = [0; N];
+        self.read(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), SnapshotError> {
+        self.0.read_exact(buf).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => SnapshotError::Corrupt("cut short"),
+            _ => SnapshotError::Io(e),
+        })
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_be_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_be_bytes(self.take()?))
+    }
+
+    /// Bytes preceded by their length.
+    fn bytes(&mut self) -> Result<Vec<u8>, SnapshotError> {
+        let len = self.u32()? as u64;
+        // checked first, so a bad length does not allocate more than is left
+        if len > self.0.limit() {
+            return Err(SnapshotError::Corrupt("cut short"));
+        }
+        let mut bytes = vec![0; len as usize];
+        self.read(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+/// Computes the checksum of everything written through it.
+struct Checksummed<W> {
+    inner: W,
+    crc: u32,
+}
+
+impl<W: Write> Checksummed<W> {
+    fn new(inner: W) -> Self {
+        Checksummed {
+            inner,
+            crc: CRC32_INIT,
+        }
+    }
+
+    fn checksum(&self) -> u32 {
+        self.crc ^ CRC32_INIT
+    }
+
+    fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.crc = crc32(self.crc, &buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub(crate) const CRC32_INIT: u32 = 0xffff_ffff;
+
+/// The table of the CRC-32 used by zlib and PNG (reversed polynomial 0xedb88320).
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Continues a CRC-32 over more data, start from [`CRC32_INIT`] and xor the result with it.
+pub(crate) fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc = CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockClock;
+    use bytes::Bytes;
+    use std::io::Cursor;
+
+    fn cache(clock: &MockClock) -> Cache<Vec<u8>, Bytes> {
+        Cache::builder()
+            .clock(clock.clone())
+            .without_vacuum_thread()
+            .build()
+    }
+
+    fn snapshot() -> Vec<u8> {
+        let cache = cache(&MockClock::new());
+        cache.insert_with_ttl(b"a".to_vec(), Bytes::from_static(b"1"), 0, 5);
+        cache.insert_with_ttl(b"b".to_vec(), Bytes::from_static(b"22"), 100, 0);
+        cache.insert_with_idle(b"c".to_vec(), Bytes::from_static(b"333"), 10, 0, 0);
+        let mut data = Vec::new();
+        assert_eq!(cache.write_snapshot(&mut data).unwrap(), 3);
+        data
+    }
+
+    #[test]
+    fn round_trips() {
+        let clock = MockClock::new();
+        let cache = cache(&clock);
+        assert_eq!(cache.read_snapshot(Cursor::new(snapshot())).unwrap(), 3);
+        let a = cache.get(b"a".as_slice()).unwrap();
+        assert_eq!((a.get_flag(), a.get_cas()), (5, 1));
+        assert_eq!(a.into_value(), "1");
+        // restored items keep their ttls and idle timeouts
+        clock.advance(Duration::from_secs(9));
+        assert!(cache.get(b"b".as_slice()).is_some());
+        clock.advance(Duration::from_secs(2));
+        assert!(cache.get(b"c".as_slice()).is_none());
+        clock.advance(Duration::from_secs(90));
+        assert!(cache.get(b"a".as_slice()).is_some());
+        assert!(cache.get(b"b".as_slice()).is_none());
+        // cas uniques are not given out again
+        assert_eq!(cache.set(b"d".to_vec(), Bytes::new(), 0, 0), 4);
+    }
+
+    #[test]
+    fn rejects_a_flipped_byte() {
+        let mut data = snapshot();
+        let middle = data.len() / 2;
+        data[middle] ^= 1;
+        let cache = cache(&MockClock::new());
+        assert!(matches!(
+            cache.read_snapshot(Cursor::new(data)),
+            Err(SnapshotError::Checksum)
+        ));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut data = snapshot();
+        data[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&2u32.to_be_bytes());
+        assert!(matches!(
+            cache(&MockClock::new()).read_snapshot(Cursor::new(data)),
+            Err(SnapshotError::UnsupportedVersion(2))
+        ));
+    }
+
+    #[test]
+    fn rejects_a_truncated_snapshot() {
+        let mut data = snapshot();
+        data.truncate(HEADER_LEN + 2);
+        assert!(matches!(
+            cache(&MockClock::new()).read_snapshot(Cursor::new(data)),
+            Err(SnapshotError::Corrupt(_))
+        ));
+    }
+}
@@ -0,0 +1,142 @@
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+/// A point in time snapshot of the cache statistics, see [`crate::Cache::stats`]. Taken from
+/// atomic counters without locking the map, so the values may be slightly apart from each other
+/// while the cache is written to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Number of items in the cache, including expired ones which are not reclaimed yet.
+    pub items: u64,
+    /// Approximate number of bytes taken by the items in the cache, their keys and values plus a
+    /// fixed overhead per item, and the copies of the keys kept by the eviction policy.
+    pub bytes: u64,
+    /// Number of lookups which found a live item.
+    pub hits: u64,
+    /// Number of lookups which found nothing or an expired item.
+    pub misses: u64,
+    /// Number of items stored under a key which had none (or an expired one).
+    pub inserts: u64,
+    /// Number of stores and in place updates of a live item.
+    pub overwrites: u64,
+    /// Number of items removed because their ttl had passed, by the vacuum thread, an eviction or
+    /// a store replacing them.
+    pub expirations: u64,
+    /// Number of live items removed to free memory.
+    pub evictions: u64,
+    /// Number of items which expired without ever being fetched.
+    pub expired_unfetched: u64,
+    /// Number of expired or flushed items removed by the vacuum thread.
+    pub reclaimed: u64,
+    /// How long the last sweep of the vacuum thread took.
+    pub last_sweep: Duration,
+    /// Number of loaders run by [`crate::Cache::get_or_load`].
+    pub loads: u64,
+    /// Number of loaders which returned an error.
+    pub load_errors: u64,
+    /// Number of [`crate::Cache::get_or_load`] calls which waited for the loader of another call
+    /// instead of running their own.
+    pub coalesced: u64,
+    /// Number of removed items not handed to the removal listeners, as too many were waiting for
+    /// them already.
+    pub dropped_notifications: u64,
+}
+
+/// The counters behind [`CacheStats`], shared by all clones of a cache and its vacuum thread.
+#[derive(Default)]
+pub(crate) struct Counters {
+    pub(crate) hits: AtomicU64,
+    pub(crate) misses: AtomicU64,
+    pub(crate) inserts: AtomicU64,
+    pub(crate) overwrites: AtomicU64,
+    pub(crate) expirations: AtomicU64,
+    pub(crate) evictions: AtomicU64,
+    pub(crate) expired_unfetched: AtomicU64,
+    pub(crate) reclaimed: AtomicU64,
+    pub(crate) last_sweep_micros: AtomicU64,
+    pub(crate) loads: AtomicU64,
+    pub(crate) load_errors: AtomicU64,
+    pub(crate) coalesced: AtomicU64,
+    pub(crate) dropped_notifications: AtomicU64,
+    // signed as concurrent updates may briefly take them below zero
+    pub(crate) items: AtomicI64,
+    pub(crate) bytes: AtomicI64,
+}
+
+impl Counters {
+    pub(crate) fn record_lookup(&self, hit: bool) {
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Accounts for a store, `overwrite` if it replaced a live item.
+    pub(crate) fn record_store(&self, overwrite: bool) {
+        if overwrite {
+            self.overwrites.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.inserts.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn add_bytes(&self, bytes: i64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Accounts for an item put into the map, taking the given bytes.
+    pub(crate) fn add_item(&self, bytes: i64) {
+        self.items.fetch_add(1, Ordering::Relaxed);
+        self.add_bytes(bytes);
+    }
+
+    /// Accounts for an item taken out of the map, which took the given bytes.
+    pub(crate) fn remove_item(&self, bytes: i64) {
+        self.items.fetch_sub(1, Ordering::Relaxed);
+        self.add_bytes(-bytes);
+    }
+
+    pub(crate) fn record_sweep(&self, reclaimed: u64, duration: Duration) {
+        self.reclaimed.fetch_add(reclaimed, Ordering::Relaxed);
+        self.last_sweep_micros
+            .store(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> CacheStats {
+        CacheStats {
+            items: self.items.load(Ordering::Relaxed).max(0) as u64,
+            bytes: self.bytes.load(Ordering::Relaxed).max(0) as u64,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            inserts: self.inserts.load(Ordering::Relaxed),
+            overwrites: self.overwrites.load(Ordering::Relaxed),
+            expirations: self.expirations.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            expired_unfetched: self.expired_unfetched.load(Ordering::Relaxed),
+            reclaimed: self.reclaimed.load(Ordering::Relaxed),
+            last_sweep: Duration::from_micros(self.last_sweep_micros.load(Ordering::Relaxed)),
+            loads: self.loads.load(Ordering::Relaxed),
+            load_errors: self.load_errors.load(Ordering::Relaxed),
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+            dropped_notifications: self.dropped_notifications.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Resets the event counters, the gauges (items and bytes) describe the current content of
+    /// the cache and are kept.
+    pub(crate) fn reset(&self) {
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+        self.inserts.store(0, Ordering::Relaxed);
+        self.overwrites.store(0, Ordering::Relaxed);
+        self.expirations.store(0, Ordering::Relaxed);
+        self.evictions.store(0, Ordering::Relaxed);
+        self.expired_unfetched.store(0, Ordering::Relaxed);
+        self.reclaimed.store(0, Ordering::Relaxed);
+        self.loads.store(0, Ordering::Relaxed);
+        self.load_errors.store(0, Ordering::Relaxed);
+        self.coalesced.store(0, Ordering::Relaxed);
+        self.dropped_notifications.store(0, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,94 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Stops the vacuum thread of a cache, see [`crate::Cache::vacuum_handle`].
+///
+/// A handle does not keep the cache alive, the thread also stops once the last clone of the
+/// cache is dropped.
+#[derive(Clone)]
+pub struct VacuumHandle {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    stopped: Mutex<bool>,
+    wake: Condvar,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl VacuumHandle {
+    /// Stops the thread and waits for a sweep in progress to finish. Does nothing if the thread
+    /// has stopped already.
+    pub fn shutdown(&self) {
+        self.shared.stop();
+        let thread = self.shared.thread.lock().unwrap().take();
+        if let Some(thread) = thread {
+            // a handle used from within a sweep can not wait for itself
+            if thread.thread().id() != thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        *self.shared.stopped.lock().unwrap()
+    }
+}
+
+impl Shared {
+    fn stop(&self) {
+        *self.stopped.lock().unwrap() = true;
+        self.wake.notify_all();
+    }
+
+    /// Sleeps for the interval, returns true if the thread was stopped meanwhile.
+    fn wait(&self, interval: Duration) -> bool {
+        let stopped = self.stopped.lock().unwrap();
+        let (stopped, _) = self
+            .wake
+            .wait_timeout_while(stopped, interval, |stopped| !*stopped)
+            .unwrap();
+        *stopped
+    }
+}
+
+/// Owned by the clones of a cache, stops the vacuum thread when the last one is dropped.
+pub(crate) struct Vacuum {
+    handle: VacuumHandle,
+}
+
+impl Vacuum {
+    /// Spawns a thread running the sweep every interval until it is stopped.
+    pub(crate) fn spawn(interval: Duration, mut sweep: impl FnMut() + Send + 'static) -> Self {
+        let shared = Arc::new(Shared {
+            stopped: Mutex::new(false),
+            wake: Condvar::new(),
+            thread: Mutex::new(None),
+        });
+        let vacuum_shared = shared.clone();
+        let thread = thread::Builder::new()
+            .name("kv-cache-vacuum".to_string())
+            .spawn(move || {
+                while !vacuum_shared.wait(interval) {
+                    sweep();
+                }
+            })
+            .expect("can not spawn the vacuum thread");
+        *shared.thread.lock().unwrap() = Some(thread);
+        Vacuum {
+            handle: VacuumHandle { shared },
+        }
+    }
+
+    pub(crate) fn handle(&self) -> VacuumHandle {
+        self.handle.clone()
+    }
+}
+
+impl Drop for Vacuum {
+    fn drop(&mut self) {
+        // not joined, the last clone may be dropped on an async runtime
+        self.handle.shared.stop();
+    }
+}
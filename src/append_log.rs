@@ -0,0 +1,375 @@
+//! Appends every change to the cache to a log file, which is replayed on top of the snapshot at
+//! startup. Changes are queued as they are made and written by a thread of their own, in
+//! batches, so a crash of the process loses the few changes still queued and a crash of the
+//! machine the ones not yet synced to disk. Under `always`, commands are only answered once their
+//! changes are synced, so nothing that was answered is lost.
+//!
+//! The log is compacted once it grows too large: new changes go to a fresh log, a snapshot is
+//! written, and the fresh log replaces the old one. Snapshots may have some of the changes of the
+//! log already: sets, touches, deletes and flushes give the same result when replayed again, and
+//! an append is only replayed on the item it was made on, so nothing is lost or applied twice if
+//! the process dies in between.
+use std::cell::Cell;
+use std::fs::{self, File};
+use std::future::Future;
+use std::io::{self, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use kv_cache::{Cache, Change, Journal, JOURNAL_HEADER};
+use log::{info, warn};
+use tokio::sync::watch;
+
+use crate::snapshot::Snapshots;
+
+/// How long changes may stay unsynced under `every-second`.
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+/// The writer thread writes at most this many bytes of changes at once.
+const MAX_BATCH_LEN: usize = 1024 * 1024;
+/// How many changes may wait for the writer thread. Changes made while it is full wait until it
+/// catches up, so a slow disk slows down the writes to the cache instead of filling the memory.
+const QUEUE_LEN: usize = 64 * 1024;
+
+thread_local! {
+    /// The number of changes queued to the log right after this thread last queued one, until
+    /// taken by [`AppendLog::synced`].
+    static QUEUED: Cell<u64> = const { Cell::new(0) };
+}
+
+/// When the log is synced to disk, i.e. how many changes a crash of the machine can lose.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fsync {
+    /// After every batch of changes, their commands are answered once it is done. Slow, nothing
+    /// is lost.
+    Always,
+    /// Once a second, at most a second of changes is lost.
+    EverySecond,
+    /// Left to the operating system.
+    Never,
+}
+
+#[derive(Clone)]
+pub struct AppendLog {
+    path: PathBuf,
+    fsync: Fsync,
+    requests: SyncSender<Request>,
+    /// Number of changes queued to the writer thread.
+    queued: Arc<AtomicU64>,
+    /// Number of changes written by the writer thread, and synced under `Fsync::Always`.
+    written: watch::Receiver<u64>,
+    /// Bytes written to the log file.
+    len: Arc<AtomicU64>,
+    /// Set while the log is compacted, so only one compaction runs at a time.
+    rewriting: Arc<AtomicBool>,
+}
+
+/// What the writer thread is asked to do, in the order it was asked.
+enum Request {
+    /// Append the record of a change.
+    Change(Vec<u8>),
+    /// Sync the log to disk.
+    Sync(SyncSender<io::Result<()>>),
+    /// Sync the log and write the changes to the fresh log of a compaction from now on.
+    Rotate(SyncSender<io::Result<()>>),
+    /// Move the fresh log of a compaction in place, the snapshot has the changes of the old one.
+    Finish(SyncSender<io::Result<()>>),
+}
+
+/// The writer thread, which owns the log file.
+struct Writer {
+    path: PathBuf,
+    fsync: Fsync,
+    file: File,
+    /// Whether the changes go to the fresh log of a compaction, which is not in place yet.
+    rotated: bool,
+    len: Arc<AtomicU64>,
+    /// Number of changes written, published once they are synced under `Fsync::Always`.
+    changes: u64,
+    written: watch::Sender<u64>,
+    /// When changes were first written since the last sync, under `Fsync::EverySecond`.
+    unsynced_since: Option<Instant>,
+}
+
+impl Writer {
+    fn run(mut self, requests: Receiver<Request>) {
+        loop {
+            if self.until_sync().is_zero() {
+                self.sync_logged();
+            }
+            let request = match requests.recv_timeout(self.until_sync()) {
+                Ok(request) => request,
+                Err(RecvTimeoutError::Timeout) => continue,
+                // the last clone of the log is gone, it was synced on shutdown
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+            // the changes queued meanwhile are written at once
+            let mut batch = Vec::new();
+            let mut changes = 0;
+            let mut next = Some(request);
+            while let Some(request) = next {
+                match request {
+                    Request::Change(record) => {
+                        batch.extend_from_slice(&record);
+                        changes += 1;
+                    }
+                    request => {
+                        self.write(&batch, changes);
+                        batch.clear();
+                        changes = 0;
+                        self.handle(request);
+                    }
+                }
+                next = if batch.len() < MAX_BATCH_LEN {
+                    requests.try_recv().ok()
+                } else {
+                    None
+                };
+            }
+            self.write(&batch, changes);
+        }
+    }
+
+    fn write(&mut self, batch: &[u8], changes: u64) {
+        if changes == 0 {
+            return;
+        }
+        let written = self.file.write_all(batch).and_then(|()| {
+            if self.fsync == Fsync::Always {
+                self.file.sync_data()?;
+            }
+            Ok(())
+        });
+        match written {
+            Ok(()) => {
+                self.len.fetch_add(batch.len() as u64, Ordering::Relaxed);
+                if self.fsync == Fsync::EverySecond {
+                    self.unsynced_since.get_or_insert_with(Instant::now);
+                }
+            }
+            Err(e) => warn!("can not append to log {}: {}", self.path.display(), e),
+        }
+        // the commands waiting for the changes are answered even if they were lost, as before
+        // the log existed
+        self.changes += changes;
+        let _ = self.written.send(self.changes);
+    }
+
+    fn handle(&mut self, request: Request) {
+        match request {
+            Request::Change(_) => unreachable!("changes are written in batches"),
+            Request::Sync(reply) => {
+                let _ = reply.send(self.sync());
+            }
+            Request::Rotate(reply) => {
+                // a previous compaction failed after rotating, its fresh log has all changes since
+                let rotated = if self.rotated {
+                    Ok(())
+                } else {
+                    self.sync().and_then(|()| {
+                        self.file = create(&rewrite_path(&self.path))?;
+                        self.len
+                            .store(JOURNAL_HEADER.len() as u64, Ordering::Relaxed);
+                        self.rotated = true;
+                        Ok(())
+                    })
+                };
+                let _ = reply.send(rotated);
+            }
+            Request::Finish(reply) => {
+                let renamed = fs::rename(rewrite_path(&self.path), &self.path);
+                if renamed.is_ok() {
+                    self.rotated = false;
+                }
+                let _ = reply.send(renamed);
+            }
+        }
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        self.unsynced_since = None;
+        Ok(())
+    }
+
+    fn sync_logged(&mut self) {
+        if let Err(e) = self.sync() {
+            warn!("can not sync log {}: {}", self.path.display(), e);
+        }
+    }
+
+    /// How long until the changes written must be synced.
+    fn until_sync(&self) -> Duration {
+        match self.unsynced_since {
+            Some(since) => SYNC_INTERVAL.saturating_sub(since.elapsed()),
+            None => Duration::MAX,
+        }
+    }
+}
+
+/// Creates an empty log.
+fn create(path: &Path) -> io::Result<File> {
+    let mut file = File::create(path)?;
+    file.write_all(JOURNAL_HEADER)?;
+    file.sync_all()?;
+    Ok(file)
+}
+
+impl AppendLog {
+    /// Replays the log, and the fresh log of a compaction cut short, on top of the cache.
+    pub fn replay(cache: &Cache<Vec<u8>, Bytes>, path: &Path) {
+        for path in [path.to_path_buf(), rewrite_path(path)] {
+            let start = Instant::now();
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => {
+                    warn!("can not open log {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            match cache.replay_journal(BufReader::new(file)) {
+                Ok(replay) => {
+                    if !replay.complete {
+                        warn!("log {} ends with a torn change", path.display());
+                    }
+                    info!(
+                        "replayed {} changes from {} in {:?}",
+                        replay.changes,
+                        path.display(),
+                        start.elapsed()
+                    );
+                }
+                Err(e) => warn!("ignoring log {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    /// Starts an empty log, the changes of the previous one must be in a snapshot by now.
+    pub fn create(path: PathBuf, fsync: Fsync) -> io::Result<Self> {
+        let file = create(&path)?;
+        match fs::remove_file(rewrite_path(&path)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let len = Arc::new(AtomicU64::new(JOURNAL_HEADER.len() as u64));
+        let (written_sender, written) = watch::channel(0);
+        let writer = Writer {
+            path: path.clone(),
+            fsync,
+            file,
+            rotated: false,
+            len: len.clone(),
+            changes: 0,
+            written: written_sender,
+            unsynced_since: None,
+        };
+        let (requests, receiver) = mpsc::sync_channel(QUEUE_LEN);
+        thread::Builder::new()
+            .name("append-log".to_string())
+            .spawn(move || writer.run(receiver))?;
+        Ok(AppendLog {
+            path,
+            fsync,
+            requests,
+            queued: Arc::new(AtomicU64::new(0)),
+            written,
+            len,
+            rewriting: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Syncs the log to disk, with the changes queued so far.
+    pub fn sync(&self) -> io::Result<()> {
+        self.request(Request::Sync)
+    }
+
+    /// Waits until the changes made so far by the calling thread are synced to disk, under
+    /// `Fsync::Always`. Take it right after the command made them, before the task yields the
+    /// thread, and await it before answering the command.
+    pub fn synced(&self) -> impl Future<Output = ()> {
+        // the count covers the changes of this thread, and maybe some of other threads
+        let queued = QUEUED.with(Cell::take);
+        let wait = self.fsync == Fsync::Always && queued > 0;
+        let mut written = self.written.clone();
+        async move {
+            while wait && *written.borrow() < queued {
+                // the writer thread is gone, nothing will be synced any more
+                if written.changed().await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Replaces the log with an empty one once the snapshot has all its changes.
+    pub fn rewrite(&self, snapshots: &Snapshots) -> io::Result<()> {
+        let start = Instant::now();
+        self.request(Request::Rotate)?;
+        snapshots.write()?;
+        self.request(Request::Finish)?;
+        info!(
+            "compacted log {} in {:?}",
+            self.path.display(),
+            start.elapsed()
+        );
+        Ok(())
+    }
+
+    /// Asks the writer thread to do something, after the changes queued so far, and waits until
+    /// it is done.
+    fn request(&self, request: fn(SyncSender<io::Result<()>>) -> Request) -> io::Result<()> {
+        let (reply, done) = mpsc::sync_channel(1);
+        let stopped = || io::Error::new(ErrorKind::BrokenPipe, "the log writer thread stopped");
+        self.requests.send(request(reply)).map_err(|_| stopped())?;
+        done.recv().unwrap_or_else(|_| Err(stopped()))
+    }
+
+    /// Compacts the log once it grows past `rewrite_size` bytes, off the runtime threads.
+    pub async fn run(self, snapshots: Snapshots, rewrite_size: u64) {
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            if self.len.load(Ordering::Relaxed) < rewrite_size
+                || self.rewriting.swap(true, Ordering::Relaxed)
+            {
+                continue;
+            }
+            let log = self.clone();
+            let snapshots = snapshots.clone();
+            // compacting takes a while, changes keep being written meanwhile
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = log.rewrite(&snapshots) {
+                    warn!("can not compact log {}: {}", log.path.display(), e);
+                }
+                log.rewriting.store(false, Ordering::Relaxed);
+            });
+        }
+    }
+}
+
+impl Journal<Vec<u8>, Bytes> for AppendLog {
+    /// Queues the change for the writer thread, it is called under the lock of the key so it does
+    /// no more than encoding the change. Waits only while the queue is full.
+    fn record(&self, change: Change<'_, Vec<u8>, Bytes>) {
+        let mut record = Vec::new();
+        change.encode(&mut record);
+        // counted before it is queued, so a count read after queueing covers it
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        if self.requests.send(Request::Change(record)).is_err() {
+            warn!(
+                "log writer thread stopped, not logging to {}",
+                self.path.display()
+            );
+        }
+        QUEUED.with(|queued| queued.set(self.queued.load(Ordering::SeqCst)));
+    }
+}
+
+/// The fresh log of a compaction, until the snapshot is written.
+fn rewrite_path(path: &Path) -> PathBuf {
+    path.with_extension("rewrite")
+}
@@ -0,0 +1,249 @@
+use log::trace;
+use tokio::io::Error;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// Max 4mb input size
+pub const MAX_FRAME_SIZE: usize = 4096 * 1024;
+
+pub struct Connection {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    cursor: usize,
+    head: usize,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream) -> Connection {
+        Connection {
+            stream,
+            // Allocate the buffer with 1kb of capacity. - 1024
+            buffer: vec![0; 1024],
+            cursor: 0,
+            head: 0,
+        }
+    }
+
+    /// Waits until a whole frame, up to and including the next `\r\n`, is buffered and returns
+    /// its length. The frame stays in the bytes of [`Connection::split`] until it is consumed, so commands
+    /// can borrow their keys from it.
+    pub async fn fill_frame(&mut self) -> io::Result<usize> {
+        loop {
+            trace!("fill_frame_loop");
+            if self.cursor > 0 {
+                for i in self.head..self.cursor - 1 {
+                    if &self.buffer[i..i + 2] == b"\r\n" {
+                        return Ok(i + 2 - self.head);
+                    }
+                }
+                // incomplete - maybe error or?
+            }
+
+            self.fill_buffer().await?;
+            trace!("fill_frame_loop - end");
+        }
+    }
+
+    /// Waits until at least `len` bytes are buffered.
+    pub async fn fill_to(&mut self, len: usize) -> io::Result<()> {
+        while self.cursor - self.head < len {
+            self.fill_buffer().await?;
+        }
+        Ok(())
+    }
+
+    /// The bytes read from the stream but not consumed yet, together with the stream to read the
+    /// bytes after them while they are borrowed.
+    pub fn split(&mut self) -> (&[u8], Rest<'_>) {
+        (
+            &self.buffer[self.head..self.cursor],
+            Rest {
+                stream: &mut self.stream,
+            },
+        )
+    }
+
+    /// Reads exactly `len` bytes, whatever they contain.
+    pub async fn read_bytes(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        self.fill_to(len).await?;
+        let bytes = self.buffer[self.head..self.head + len].to_vec();
+        self.consume(len);
+        Ok(bytes)
+    }
+
+    /// Returns the next byte without consuming it.
+    pub async fn peek_byte(&mut self) -> io::Result<u8> {
+        if self.cursor == self.head {
+            self.fill_buffer().await?;
+        }
+        Ok(self.buffer[self.head])
+    }
+
+    pub async fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        trace!("write_frame - '{}'", String::from_utf8_lossy(frame));
+        self.stream.write_all(frame).await?;
+        self.stream.write_all(b"\r\n").await?;
+
+        self.stream.flush().await?;
+
+        Ok(())
+    }
+
+    /// Writes the bytes as is, without a trailing `\r\n`.
+    pub async fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.stream.write_all(bytes).await?;
+        self.stream.flush().await
+    }
+
+    /// Drops the first `len` buffered bytes, once they have been handled.
+    pub fn consume(&mut self, len: usize) {
+        self.head += len;
+        if self.head == self.cursor {
+            // nothing left behind the consumed bytes
+            self.cursor = 0;
+            self.head = 0;
+        }
+    }
+
+    /// Reads more data from the stream, making room in the buffer first if it is full.
+    async fn fill_buffer(&mut self) -> io::Result<()> {
+        if self.buffer.len() == self.cursor {
+            if self.head > 0 {
+                // Move the unconsumed data to the front instead of growing the buffer
+                self.buffer.copy_within(self.head..self.cursor, 0);
+                self.cursor -= self.head;
+                self.head = 0;
+            } else {
+                let new_len = self.cursor * 2;
+                if new_len > MAX_FRAME_SIZE {
+                    self.cursor = 0;
+                    return Err(Error::from(io::ErrorKind::FileTooLarge));
+                }
+                trace!("fill_buffer - buffer resize {}", new_len);
+                // Grow the buffer
+                self.buffer.resize(new_len, 0);
+            }
+        }
+
+        // Read into the buffer, tracking the number
+        // of bytes read
+        let n = self.stream.read(&mut self.buffer[self.cursor..]).await?;
+        if 0 == n {
+            // Maybe use a different error for the case of a partial frame left in the buffer?
+            self.cursor = 0;
+            self.head = 0;
+            return Err(Error::from(io::ErrorKind::ConnectionReset));
+        }
+        // Update our cursor
+        self.cursor += n;
+        Ok(())
+    }
+}
+
+/// Reads from the stream past the buffered bytes, see [`Connection::split`].
+pub struct Rest<'a> {
+    stream: &'a mut TcpStream,
+}
+
+impl Rest<'_> {
+    /// Fills `buf` from the stream, bypassing the buffer.
+    pub async fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.stream.read_exact(buf).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    /// A connection and the client end of its stream.
+    async fn connect() -> (Connection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        client.set_nodelay(true).unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (Connection::new(server), client)
+    }
+
+    /// Writes the pieces one by one, giving the connection time to read each on its own.
+    async fn write_pieces(client: &mut TcpStream, pieces: &[&[u8]]) {
+        for piece in pieces {
+            client.write_all(piece).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_a_data_block_holding_line_ends() {
+        let (mut connection, mut client) = connect().await;
+        client
+            .write_all(b"set k 0 0 6\r\na\r\nb\r\n\r\nget k\r\n")
+            .await
+            .unwrap();
+
+        let frame_len = connection.fill_frame().await.unwrap();
+        assert_eq!(&connection.split().0[..frame_len], b"set k 0 0 6\r\n");
+        connection.consume(frame_len);
+        // the block is read by length, the line ends inside it do not end it
+        assert_eq!(connection.read_bytes(8).await.unwrap(), b"a\r\nb\r\n\r\n");
+        let frame_len = connection.fill_frame().await.unwrap();
+        assert_eq!(&connection.split().0[..frame_len], b"get k\r\n");
+    }
+
+    #[tokio::test]
+    async fn waits_for_a_data_block_split_across_reads() {
+        let (mut connection, mut client) = connect().await;
+        let pieces: [&[u8]; 5] = [b"se", b"t k 0 0 7\r", b"\nab", b"c\r", b"\nde\r\n"];
+        let writer = write_pieces(&mut client, &pieces);
+        let reader = async {
+            let frame_len = connection.fill_frame().await.unwrap();
+            assert_eq!(frame_len, 13);
+            connection.fill_to(frame_len + 9).await.unwrap();
+            assert_eq!(connection.split().0, b"set k 0 0 7\r\nabc\r\nde\r\n");
+            connection.consume(frame_len + 9);
+            assert!(connection.split().0.is_empty());
+        };
+        tokio::join!(writer, reader);
+    }
+
+    #[tokio::test]
+    async fn reads_past_the_buffered_bytes() {
+        let (mut connection, mut client) = connect().await;
+        let pieces: [&[u8]; 2] = [b"set k 0 0 7\r\nab", b"c\r\nde\r\nget k\r\n"];
+        let writer = write_pieces(&mut client, &pieces);
+        let reader = async {
+            let frame_len = connection.fill_frame().await.unwrap();
+            let (buffered, mut rest) = connection.split();
+            assert_eq!(&buffered[frame_len..], b"ab");
+            let mut block = [0; 7];
+            rest.read_exact(&mut block).await.unwrap();
+            assert_eq!(&block, b"c\r\nde\r\n");
+            connection.consume(frame_len + 2);
+            let frame_len = connection.fill_frame().await.unwrap();
+            assert_eq!(&connection.split().0[..frame_len], b"get k\r\n");
+        };
+        tokio::join!(writer, reader);
+    }
+
+    #[tokio::test]
+    async fn moves_unconsumed_data_to_the_front_of_a_full_buffer() {
+        let (mut connection, mut client) = connect().await;
+        let block = vec![b'x'; 1000];
+        let mut first = b"set k 0 0 1000\r\n".to_vec();
+        first.extend_from_slice(&block[..500]);
+        let pieces: [&[u8]; 2] = [&first, &block[500..]];
+        let writer = write_pieces(&mut client, &pieces);
+        let reader = async {
+            let frame_len = connection.fill_frame().await.unwrap();
+            connection.consume(frame_len);
+            // the block goes past the end of the 1kb buffer
+            assert_eq!(connection.read_bytes(1000).await.unwrap(), block);
+        };
+        tokio::join!(writer, reader);
+    }
+}
@@ -9,7 +9,11 @@ use prometheus::{Encoder, TextEncoder};
 
 use kv_cache::Cache;
 
-use crate::metrics::{METRIC_CACHE_SIZE, METRIC_REQUEST_DURATION};
+use crate::metrics::{
+    METRIC_CACHE_BYTES, METRIC_CACHE_EVICTIONS, METRIC_CACHE_EXPIRATIONS, METRIC_CACHE_HITS,
+    METRIC_CACHE_INSERTS, METRIC_CACHE_MISSES, METRIC_CACHE_OVERWRITES, METRIC_CACHE_SIZE,
+    METRIC_REQUEST_DURATION, METRIC_SWEEP_DURATION, METRIC_SWEEP_RECLAIMED,
+};
 
 pub struct HttpServer<K: Eq + Hash + Send + Sync + 'static, V: Send + Sync + 'static> {
     cache: Cache<K, V>,
@@ -24,10 +28,20 @@ impl<K: Eq + Hash + Send + Sync + 'static, V: Send + Sync + 'static> HttpServer<
         // metrics
         let addr = ([127, 0, 0, 1], 9001).into();
 
-        // start size metric reporting thread
+        // start cache metric reporting thread
         let cache = self.cache.clone();
         thread::spawn(move || loop {
-            METRIC_CACHE_SIZE.set(cache.len() as f64);
+            let stats = cache.stats();
+            METRIC_CACHE_SIZE.set(stats.items as f64);
+            METRIC_CACHE_BYTES.set(stats.bytes as f64);
+            METRIC_CACHE_HITS.set(stats.hits as f64);
+            METRIC_CACHE_MISSES.set(stats.misses as f64);
+            METRIC_CACHE_INSERTS.set(stats.inserts as f64);
+            METRIC_CACHE_OVERWRITES.set(stats.overwrites as f64);
+            METRIC_CACHE_EXPIRATIONS.set(stats.expirations as f64);
+            METRIC_CACHE_EVICTIONS.set(stats.evictions as f64);
+            METRIC_SWEEP_DURATION.set(stats.last_sweep.as_secs_f64());
+            METRIC_SWEEP_RECLAIMED.set(stats.reclaimed as f64);
             thread::sleep(Duration::from_secs(5));
         });
 
@@ -94,5 +108,5 @@ where
     METRIC_REQUEST_DURATION
         .with_label_values(&[result.1])
         .observe(duration.as_secs_f64());
-    return result.0;
+    result.0
 }
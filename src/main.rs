@@ -1,26 +1,156 @@
-#![feature(io_error_more)]
-
 extern crate core;
 
+mod append_log;
+mod connection;
 mod http_server;
+mod memcache_binary;
+mod memcache_meta;
 mod memcache_server;
 mod metrics;
 mod parser;
+mod snapshot;
+mod stats;
 
-use std;
+use std::path::PathBuf;
 use std::time::Duration;
 
-use kv_cache::Cache;
+use bytes::Bytes;
+use kv_cache::{Cache, Lfu, Lru, WTinyLfu};
+use log::{info, warn};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::append_log::{AppendLog, Fsync};
+use crate::snapshot::Snapshots;
 
 const EXPIRE_DURATION: Duration = Duration::from_secs(3600);
+/// Memory budget of the cache in megabytes, like memcached's `-m`. Unbounded when not set.
+const MAX_MEMORY_ENV: &str = "MEMC_KV_MAX_MEMORY_MB";
+/// Which items to evict once the memory budget is used up: `lru` (default), `lfu` or `w-tinylfu`.
+const EVICTION_POLICY_ENV: &str = "MEMC_KV_EVICTION_POLICY";
+/// Seconds after which items expire when they are not accessed, on top of their ttl. Items only
+/// expire by ttl when not set.
+const IDLE_TIMEOUT_ENV: &str = "MEMC_KV_IDLE_TIMEOUT_SECS";
+/// File the cache is restored from at startup and saved to periodically and on shutdown. Nothing
+/// is persisted when not set.
+const SNAPSHOT_PATH_ENV: &str = "MEMC_KV_SNAPSHOT_PATH";
+/// Seconds between two snapshots, 300 by default.
+const SNAPSHOT_INTERVAL_ENV: &str = "MEMC_KV_SNAPSHOT_INTERVAL_SECS";
+const DEFAULT_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(300);
+/// File every change to the cache is appended to, replayed on top of the snapshot at startup.
+/// Needs a snapshot path, the log is compacted into the snapshot. No log when not set.
+const APPEND_LOG_PATH_ENV: &str = "MEMC_KV_APPEND_LOG_PATH";
+/// When the log is synced to disk: `always`, `every-second` (default) or `never`.
+const APPEND_LOG_FSYNC_ENV: &str = "MEMC_KV_APPEND_LOG_FSYNC";
+/// Size in megabytes past which the log is compacted, 64 by default.
+const APPEND_LOG_REWRITE_ENV: &str = "MEMC_KV_APPEND_LOG_REWRITE_MB";
+const DEFAULT_APPEND_LOG_REWRITE_MB: u64 = 64;
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 8)]
 async fn main() {
     env_logger::init();
 
-    let cache = Cache::<Vec<u8>, Vec<u8>>::new(Some(EXPIRE_DURATION));
+    let max_bytes = std::env::var(MAX_MEMORY_ENV).ok().map(|mb| {
+        let mb: usize = mb
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be a number of megabytes", MAX_MEMORY_ENV));
+        mb * 1024 * 1024
+    });
+    let idle_timeout = std::env::var(IDLE_TIMEOUT_ENV).ok().map(|secs| {
+        let secs: u64 = secs
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be a number of seconds", IDLE_TIMEOUT_ENV));
+        Duration::from_secs(secs)
+    });
+    let policy = std::env::var(EVICTION_POLICY_ENV).unwrap_or_else(|_| "lru".to_string());
+    let mut builder = Cache::<Vec<u8>, Bytes>::builder().default_ttl(EXPIRE_DURATION);
+    builder = match (max_bytes, policy.as_str()) {
+        (None, _) => builder,
+        (Some(max_bytes), "lru") => builder.eviction_policy(max_bytes, Lru::new()),
+        (Some(max_bytes), "lfu") => builder.eviction_policy(max_bytes, Lfu::new()),
+        (Some(max_bytes), "w-tinylfu") => builder.eviction_policy(max_bytes, WTinyLfu::new()),
+        (Some(_), policy) => panic!("unknown {}: {}", EVICTION_POLICY_ENV, policy),
+    };
+    if let Some(idle_timeout) = idle_timeout {
+        builder = builder.time_to_idle(idle_timeout);
+    }
+    let cache = builder.build();
+
+    let snapshots = std::env::var(SNAPSHOT_PATH_ENV)
+        .ok()
+        .map(|path| Snapshots::new(cache.clone(), PathBuf::from(path)));
+    if let Some(snapshots) = &snapshots {
+        // restored before the servers accept connections
+        snapshots.load();
+        let interval =
+            std::env::var(SNAPSHOT_INTERVAL_ENV)
+                .ok()
+                .map_or(DEFAULT_SNAPSHOT_INTERVAL, |secs| {
+                    let secs: u64 = secs.parse().unwrap_or_else(|_| {
+                        panic!("{} must be a number of seconds", SNAPSHOT_INTERVAL_ENV)
+                    });
+                    Duration::from_secs(secs)
+                });
+        tokio::spawn(snapshots.clone().write_periodically(interval));
+    }
+
+    let append_log = std::env::var(APPEND_LOG_PATH_ENV).ok().map(|path| {
+        let snapshots = snapshots.clone().unwrap_or_else(|| {
+            panic!(
+                "{} needs {} to be set",
+                APPEND_LOG_PATH_ENV, SNAPSHOT_PATH_ENV
+            )
+        });
+        let fsync = match std::env::var(APPEND_LOG_FSYNC_ENV).as_deref() {
+            Ok("always") => Fsync::Always,
+            Ok("every-second") | Err(_) => Fsync::EverySecond,
+            Ok("never") => Fsync::Never,
+            Ok(fsync) => panic!("unknown {}: {}", APPEND_LOG_FSYNC_ENV, fsync),
+        };
+        let rewrite_mb = std::env::var(APPEND_LOG_REWRITE_ENV).ok().map_or(
+            DEFAULT_APPEND_LOG_REWRITE_MB,
+            |mb| {
+                mb.parse().unwrap_or_else(|_| {
+                    panic!("{} must be a number of megabytes", APPEND_LOG_REWRITE_ENV)
+                })
+            },
+        );
+        let path = PathBuf::from(path);
+        AppendLog::replay(&cache, &path);
+        // the replayed changes go into a snapshot, so the log can start over
+        snapshots
+            .write()
+            .unwrap_or_else(|e| panic!("can not write snapshot before starting the log: {}", e));
+        let log = AppendLog::create(path, fsync)
+            .unwrap_or_else(|e| panic!("can not create {}: {}", APPEND_LOG_PATH_ENV, e));
+        cache.set_journal(log.clone());
+        tokio::spawn(log.clone().run(snapshots, rewrite_mb * 1024 * 1024));
+        log
+    });
+
     let http_server = http_server::HttpServer::new(cache.clone());
-    let memcache_server = memcache_server::MemcacheServer::new(cache.clone());
+    let memcache_server = memcache_server::MemcacheServer::new(cache.clone(), append_log.clone());
+
+    tokio::select! {
+        _ = async { tokio::join!(http_server.serve(), memcache_server.serve()) } => {}
+        _ = shutdown_signal() => info!("shutting down"),
+    }
+    if let Some(snapshots) = &snapshots {
+        if let Err(e) = snapshots.write() {
+            warn!("can not write snapshot on shutdown: {}", e);
+        }
+    }
+    if let Some(append_log) = &append_log {
+        if let Err(e) = append_log.sync() {
+            warn!("can not sync log on shutdown: {}", e);
+        }
+    }
+}
 
-    let (_, _) = tokio::join!(http_server.serve(), memcache_server.serve());
+/// Resolves on ctrl-c or SIGTERM, e.g. when the server is stopped during a rollout.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("can not listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
 }
@@ -0,0 +1,384 @@
+use crate::append_log::AppendLog;
+use crate::connection::{Connection, MAX_FRAME_SIZE};
+use crate::memcache_server::{execute, observe_duration, relative_exptime, Reply, VERSION};
+use crate::parser::binary::{
+    opcode, parse_binary_header, parse_binary_request, status, Header, HEADER_LEN, MAGIC_RESPONSE,
+    NO_AUTO_CREATE,
+};
+use crate::parser::Cmd;
+use crate::stats::ServerStats;
+use bytes::Bytes;
+use kv_cache::Cache;
+use log::{debug, trace};
+use nom::error::ErrorKind;
+use std::time::SystemTime;
+use tokio::io::{self, Error};
+
+/// A response packet, not encoded yet.
+struct Response {
+    status: u16,
+    cas: u64,
+    extras: Vec<u8>,
+    key: Vec<u8>,
+    value: Bytes,
+}
+
+impl Response {
+    fn ok(value: Bytes) -> Self {
+        Response {
+            status: status::NO_ERROR,
+            cas: 0,
+            extras: Vec::new(),
+            key: Vec::new(),
+            value,
+        }
+    }
+
+    /// An error response, carrying a human readable message as value like memcached does.
+    fn error(status: u16) -> Self {
+        let message: &'static [u8] = match status {
+            status::KEY_NOT_FOUND => b"Not found",
+            status::KEY_EXISTS => b"Data exists for key.",
+            status::VALUE_TOO_LARGE => b"Too large.",
+            status::INVALID_ARGUMENTS => b"Invalid arguments",
+            status::NOT_STORED => b"Not stored.",
+            status::NON_NUMERIC => b"Non-numeric server-side value for incr or decr",
+            _ => b"Unknown command",
+        };
+        Response {
+            status,
+            ..Response::ok(Bytes::from_static(message))
+        }
+    }
+
+    /// Writes the packet, echoing the opcode and opaque of the request. The value is written as
+    /// is after the rest of the packet, without copying it.
+    async fn write(&self, connection: &mut Connection, request: &Header) -> io::Result<()> {
+        connection.write_bytes(&self.encode_head(request)).await?;
+        if !self.value.is_empty() {
+            connection.write_bytes(&self.value).await?;
+        }
+        Ok(())
+    }
+
+    /// Encodes the packet up to the value: the header, extras and key.
+    fn encode_head(&self, request: &Header) -> Vec<u8> {
+        let body_len = self.extras.len() + self.key.len() + self.value.len();
+        let mut packet = Vec::with_capacity(HEADER_LEN + self.extras.len() + self.key.len());
+        packet.push(MAGIC_RESPONSE);
+        packet.push(request.opcode);
+        packet.extend_from_slice(&(self.key.len() as u16).to_be_bytes());
+        packet.push(self.extras.len() as u8);
+        // data type, always raw bytes
+        packet.push(0);
+        packet.extend_from_slice(&self.status.to_be_bytes());
+        packet.extend_from_slice(&(body_len as u32).to_be_bytes());
+        packet.extend_from_slice(&request.opaque.to_be_bytes());
+        packet.extend_from_slice(&self.cas.to_be_bytes());
+        packet.extend_from_slice(&self.extras);
+        packet.extend_from_slice(&self.key);
+        packet
+    }
+}
+
+/// Serves binary requests until the client quits or the connection breaks.
+pub async fn process(
+    connection: &mut Connection,
+    cache: &Cache<Vec<u8>, Bytes>,
+    stats: &ServerStats,
+    log: Option<&AppendLog>,
+) -> io::Result<()> {
+    loop {
+        let header = connection.read_bytes(HEADER_LEN).await?;
+        let start_time = SystemTime::now();
+        let header = match parse_binary_header(&header) {
+            Ok((_, header)) => header,
+            Err(e) => {
+                // without a valid header there is no telling where the next request starts
+                debug!("invalid binary header: {}", e);
+                return Err(Error::from(io::ErrorKind::InvalidData));
+            }
+        };
+        trace!("binary header: {:?}", header);
+        if header.body_len as usize > MAX_FRAME_SIZE {
+            // the body can not be buffered, so there is no telling where the next request starts
+            Response::error(status::VALUE_TOO_LARGE)
+                .write(connection, &header)
+                .await?;
+            return Err(Error::from(io::ErrorKind::FileTooLarge));
+        }
+        let body = Bytes::from(connection.read_bytes(header.body_len as usize).await?);
+        let request = match parse_binary_request(&header, &body) {
+            Ok((_, request)) => request,
+            Err(e) => {
+                debug!("binary parse error: {}", e);
+                let status = match e {
+                    nom::Err::Error(e) if e.code == ErrorKind::Tag => status::UNKNOWN_COMMAND,
+                    _ => status::INVALID_ARGUMENTS,
+                };
+                Response::error(status).write(connection, &header).await?;
+                continue;
+            }
+        };
+
+        let cmd = match request.cmd {
+            Some(cmd) => cmd,
+            // noop and quit
+            None => {
+                if header.opcode != opcode::QUITQ {
+                    Response::ok(Bytes::new())
+                        .write(connection, &header)
+                        .await?;
+                }
+                if header.opcode == opcode::NOOP {
+                    continue;
+                }
+                return Ok(());
+            }
+        };
+        let name = cmd.name();
+        let create = match request.initial {
+            Some((initial, ttl)) if ttl != NO_AUTO_CREATE => Some((cmd.clone(), initial, ttl)),
+            _ => None,
+        };
+        // the value is stored as a slice of the body, without copying it
+        let mut reply = execute(cache, stats, cmd, body.slice_ref(request.value));
+        if let (Reply::NotFound, Some((cmd, initial, ttl))) = (&reply, create) {
+            reply = create_counter(cache, stats, cmd, initial, ttl);
+        }
+        // the changes of the command are on disk before the client hears of them
+        if let Some(synced) = log.map(AppendLog::synced) {
+            synced.await;
+        }
+
+        let mut response = response(header.opcode, reply);
+        if matches!(header.opcode, opcode::GETK | opcode::GETKQ) {
+            let key_start = header.extras_len as usize;
+            response.key = body[key_start..key_start + header.key_len as usize].to_vec();
+        }
+        // quiet gets only answer hits, other quiet commands only answer failures
+        let silent = opcode::is_quiet(header.opcode)
+            && if opcode::is_get(header.opcode) {
+                response.status == status::KEY_NOT_FOUND
+            } else {
+                response.status == status::NO_ERROR
+            };
+        if !silent {
+            response.write(connection, &header).await?;
+        }
+        observe_duration(name, start_time);
+    }
+}
+
+/// Creates a missing counter with the initial value of an incr or decr request, the delta only
+/// applies to counters that exist already.
+fn create_counter(
+    cache: &Cache<Vec<u8>, Bytes>,
+    stats: &ServerStats,
+    cmd: Cmd<'_>,
+    initial: u64,
+    ttl: u32,
+) -> Reply {
+    let key = match &cmd {
+        Cmd::CmdIncr { key, .. } | Cmd::CmdDecr { key, .. } => key.to_vec(),
+        _ => return Reply::NotFound,
+    };
+    // the ttl is an exptime like the one of set
+    let exptime = relative_exptime(ttl);
+    match cache.add(
+        key.clone(),
+        Bytes::from(initial.to_string()),
+        exptime.unwrap_or(0),
+        0,
+    ) {
+        Some(cas) => {
+            // the exptime has passed, the counter is gone right away
+            if exptime.is_none() {
+                cache.remove(&key);
+            }
+            Reply::Counter(initial, cas)
+        }
+        // another client created the counter in the meantime
+        None => execute(cache, stats, cmd, Bytes::new()),
+    }
+}
+
+/// Maps the reply of a command onto a binary response.
+fn response(opcode: u8, reply: Reply) -> Response {
+    match reply {
+        Reply::Values { mut items, .. } => match items.pop() {
+            Some((_, item)) => Response {
+                cas: item.get_cas(),
+                extras: item.get_flag().to_be_bytes().to_vec(),
+                ..Response::ok(item.into_value())
+            },
+            None => Response::error(status::KEY_NOT_FOUND),
+        },
+        Reply::Stored(cas) => Response {
+            cas,
+            ..Response::ok(Bytes::new())
+        },
+        Reply::Deleted | Reply::Touched | Reply::Ok | Reply::Reset => Response::ok(Bytes::new()),
+        Reply::NotStored => Response::error(match opcode {
+            opcode::ADD | opcode::ADDQ => status::KEY_EXISTS,
+            opcode::REPLACE | opcode::REPLACEQ => status::KEY_NOT_FOUND,
+            _ => status::NOT_STORED,
+        }),
+        Reply::Exists => Response::error(status::KEY_EXISTS),
+        Reply::NotFound => Response::error(status::KEY_NOT_FOUND),
+        Reply::Counter(n, cas) => Response {
+            cas,
+            ..Response::ok(Bytes::copy_from_slice(&n.to_be_bytes()))
+        },
+        Reply::NonNumeric => Response::error(status::NON_NUMERIC),
+        Reply::Version => Response::ok(Bytes::from_static(VERSION.as_bytes())),
+        // the binary parser never produces stats or meta commands
+        Reply::Stats(_) | Reply::Meta(_) => Response::error(status::UNKNOWN_COMMAND),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::binary::MAGIC_REQUEST;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// A decoded response packet.
+    #[derive(Debug, PartialEq)]
+    struct Packet {
+        opcode: u8,
+        status: u16,
+        opaque: u32,
+        cas: u64,
+        extras: Vec<u8>,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    }
+
+    /// Serves binary requests on a fresh cache and returns the client end of the connection.
+    async fn serve_binary() -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move {
+            let cache = Cache::builder().without_vacuum_thread().build();
+            let mut connection = Connection::new(socket);
+            let _ = process(&mut connection, &cache, &ServerStats::new(), None).await;
+        });
+        client
+    }
+
+    fn request(opcode: u8, opaque: u32, extras: &[u8], key: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut packet = vec![MAGIC_REQUEST, opcode];
+        packet.extend_from_slice(&(key.len() as u16).to_be_bytes());
+        packet.push(extras.len() as u8);
+        packet.extend_from_slice(&[0, 0, 0]);
+        let body_len = extras.len() + key.len() + value.len();
+        packet.extend_from_slice(&(body_len as u32).to_be_bytes());
+        packet.extend_from_slice(&opaque.to_be_bytes());
+        packet.extend_from_slice(&0u64.to_be_bytes());
+        packet.extend_from_slice(extras);
+        packet.extend_from_slice(key);
+        packet.extend_from_slice(value);
+        packet
+    }
+
+    /// The extras of a set: the flag and the exptime.
+    fn store_extras(flag: u32, exptime: u32) -> Vec<u8> {
+        [flag.to_be_bytes(), exptime.to_be_bytes()].concat()
+    }
+
+    async fn read_packet(client: &mut TcpStream) -> Packet {
+        let mut head = [0; HEADER_LEN];
+        tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut head))
+            .await
+            .expect("no response")
+            .unwrap();
+        assert_eq!(head[0], MAGIC_RESPONSE);
+        let key_len = u16::from_be_bytes([head[2], head[3]]) as usize;
+        let extras_len = head[4] as usize;
+        let body_len = u32::from_be_bytes(head[8..12].try_into().unwrap()) as usize;
+        let mut body = vec![0; body_len];
+        client.read_exact(&mut body).await.unwrap();
+        Packet {
+            opcode: head[1],
+            status: u16::from_be_bytes([head[6], head[7]]),
+            opaque: u32::from_be_bytes(head[12..16].try_into().unwrap()),
+            cas: u64::from_be_bytes(head[16..24].try_into().unwrap()),
+            extras: body[..extras_len].to_vec(),
+            key: body[extras_len..extras_len + key_len].to_vec(),
+            value: body[extras_len + key_len..].to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn quiet_commands_only_answer_failures() {
+        let mut client = serve_binary().await;
+        let requests = [
+            request(opcode::SETQ, 1, &store_extras(0, 0), b"a", b"1"),
+            request(opcode::GETQ, 2, &[], b"missing", b""),
+            request(opcode::ADDQ, 3, &store_extras(0, 0), b"a", b"2"),
+            request(opcode::GETQ, 4, &[], b"a", b""),
+            request(opcode::DELETEQ, 5, &[], b"a", b""),
+            request(opcode::NOOP, 6, &[], b"", b""),
+        ]
+        .concat();
+        client.write_all(&requests).await.unwrap();
+
+        let add = read_packet(&mut client).await;
+        assert_eq!((add.opaque, add.status), (3, status::KEY_EXISTS));
+        let get = read_packet(&mut client).await;
+        assert_eq!((get.opaque, get.status), (4, status::NO_ERROR));
+        assert_eq!(get.value, b"1");
+        // the noop flushes out the quiet commands, its response comes last
+        let noop = read_packet(&mut client).await;
+        assert_eq!((noop.opaque, noop.status), (6, status::NO_ERROR));
+    }
+
+    #[tokio::test]
+    async fn getk_returns_the_key() {
+        let mut client = serve_binary().await;
+        let requests = [
+            request(opcode::SET, 1, &store_extras(7, 0), b"key", b"value"),
+            request(opcode::GETK, 2, &[], b"key", b""),
+            request(opcode::GETK, 3, &[], b"nope", b""),
+        ]
+        .concat();
+        client.write_all(&requests).await.unwrap();
+
+        let set = read_packet(&mut client).await;
+        assert_eq!(
+            read_packet(&mut client).await,
+            Packet {
+                opcode: opcode::GETK,
+                status: status::NO_ERROR,
+                opaque: 2,
+                cas: set.cas,
+                extras: 7u32.to_be_bytes().to_vec(),
+                key: b"key".to_vec(),
+                value: b"value".to_vec(),
+            }
+        );
+        let miss = read_packet(&mut client).await;
+        assert_eq!(miss.status, status::KEY_NOT_FOUND);
+        assert_eq!(miss.key, b"nope");
+    }
+
+    #[tokio::test]
+    async fn refuses_a_body_larger_than_a_frame() {
+        let mut client = serve_binary().await;
+        let mut packet = request(opcode::SET, 1, &store_extras(0, 0), b"k", b"");
+        packet[8..12].copy_from_slice(&(MAX_FRAME_SIZE as u32 + 1).to_be_bytes());
+        // the server answers before the body comes
+        client.write_all(&packet[..HEADER_LEN]).await.unwrap();
+
+        let response = read_packet(&mut client).await;
+        assert_eq!(response.status, status::VALUE_TOO_LARGE);
+        // the connection is closed as the next request can not be found
+        assert_eq!(client.read(&mut [0; 1]).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,448 @@
+//! Execution of the meta commands (mg, ms, md, ma, me) against the cache, every function returns
+//! the response to write back or None when the quiet flag suppresses it.
+use crate::memcache_server::relative_exptime;
+use crate::parser::meta::MetaFlags;
+use bytes::Bytes;
+use kv_cache::{
+    Cache, MetaArithmeticOptions, MetaDeleteOptions, MetaError, MetaGetOptions, MetaItem,
+    MetaSetMode, MetaSetOptions,
+};
+use std::borrow::Cow;
+use std::str::FromStr;
+
+type Response = Result<Option<Vec<u8>>, &'static str>;
+
+pub fn meta_get(
+    cache: &Cache<Vec<u8>, Bytes>,
+    raw_key: &[u8],
+    flags: &MetaFlags,
+) -> Option<Vec<u8>> {
+    respond(get(cache, raw_key, flags))
+}
+
+pub fn meta_set(
+    cache: &Cache<Vec<u8>, Bytes>,
+    raw_key: &[u8],
+    flags: &MetaFlags,
+    data: Bytes,
+) -> Option<Vec<u8>> {
+    respond(set(cache, raw_key, flags, data))
+}
+
+pub fn meta_delete(
+    cache: &Cache<Vec<u8>, Bytes>,
+    raw_key: &[u8],
+    flags: &MetaFlags,
+) -> Option<Vec<u8>> {
+    respond(delete(cache, raw_key, flags))
+}
+
+pub fn meta_arithmetic(
+    cache: &Cache<Vec<u8>, Bytes>,
+    raw_key: &[u8],
+    flags: &MetaFlags,
+) -> Option<Vec<u8>> {
+    respond(arithmetic(cache, raw_key, flags))
+}
+
+pub fn meta_debug(
+    cache: &Cache<Vec<u8>, Bytes>,
+    raw_key: &[u8],
+    flags: &MetaFlags,
+) -> Option<Vec<u8>> {
+    respond(debug(cache, raw_key, flags))
+}
+
+fn get(cache: &Cache<Vec<u8>, Bytes>, raw_key: &[u8], flags: &MetaFlags) -> Response {
+    let key = decode_key(raw_key, flags)?;
+    let touch_ttl = ttl(flags, b'T')?;
+    let vivify_ttl = ttl(flags, b'N')?;
+    let opts = MetaGetOptions {
+        touch_ttl: touch_ttl.flatten(),
+        vivify_ttl: vivify_ttl.flatten(),
+        recache_ttl: number(flags, b'R')?,
+        no_bump: flags.has(b'u'),
+    };
+    let item = match cache.meta_get(key.to_vec(), opts) {
+        Some(item) if touch_ttl == Some(None) => {
+            cache.remove(&*key);
+            Some(item)
+        }
+        // the empty item would expire at once, the caller still wins the right to fill it
+        None if vivify_ttl == Some(None) => Some(expired_item(Bytes::new(), Some(true))),
+        item => item,
+    };
+    match item {
+        Some(item) => Ok(Some(value_response(flags, raw_key, &item, flags.has(b'v')))),
+        None if flags.has(b'q') => Ok(None),
+        None => Ok(Some(b"EN".to_vec())),
+    }
+}
+
+fn set(cache: &Cache<Vec<u8>, Bytes>, raw_key: &[u8], flags: &MetaFlags, data: Bytes) -> Response {
+    let key = decode_key(raw_key, flags)?;
+    let mode = match flags.token(b'M').and_then(|token| token.first()) {
+        None => MetaSetMode::Set,
+        Some(b'S' | b's') => MetaSetMode::Set,
+        Some(b'E' | b'e') => MetaSetMode::Add,
+        Some(b'R' | b'r') => MetaSetMode::Replace,
+        Some(b'A' | b'a') => MetaSetMode::Append,
+        Some(b'P' | b'p') => MetaSetMode::Prepend,
+        Some(_) => return Err("invalid mode for ms"),
+    };
+    let store_ttl = ttl(flags, b'T')?;
+    let vivify_ttl = ttl(flags, b'N')?;
+    let appends = matches!(mode, MetaSetMode::Append | MetaSetMode::Prepend);
+    let opts = MetaSetOptions {
+        mode,
+        ttl: store_ttl.flatten().unwrap_or(0),
+        flag: number(flags, b'F')?.unwrap_or(0),
+        compare_cas: number(flags, b'C')?,
+        new_cas: number(flags, b'E')?,
+        invalidate: flags.has(b'I'),
+        vivify_ttl: vivify_ttl.flatten(),
+        idle: number(flags, b'A')?,
+    };
+    let new_cas = opts.new_cas;
+    let stored = match cache.meta_set(key.to_vec(), data, opts) {
+        // appends keep the ttl of the item
+        Ok(cas) if store_ttl == Some(None) && !appends => {
+            cache.remove(&*key);
+            Ok(cas)
+        }
+        // the item would have been created expired
+        Err(MetaError::NotStored) if appends && vivify_ttl == Some(None) => {
+            Ok(new_cas.unwrap_or(0))
+        }
+        stored => stored,
+    };
+    match stored {
+        Ok(_) if flags.has(b'q') => Ok(None),
+        Ok(cas) => {
+            let mut out = b"HD".to_vec();
+            return_flags(&mut out, flags, raw_key, None);
+            if flags.has(b'c') {
+                out.extend_from_slice(format!(" c{}", cas).as_bytes());
+            }
+            Ok(Some(out))
+        }
+        Err(e) => Ok(Some(error_response(flags, raw_key, e))),
+    }
+}
+
+fn delete(cache: &Cache<Vec<u8>, Bytes>, raw_key: &[u8], flags: &MetaFlags) -> Response {
+    let key = decode_key(raw_key, flags)?.into_owned();
+    let invalidate_ttl = ttl(flags, b'T')?;
+    // an invalidated item which expires at once is simply deleted
+    let expires = invalidate_ttl == Some(None);
+    let opts = MetaDeleteOptions {
+        compare_cas: number(flags, b'C')?,
+        new_cas: number(flags, b'E')?,
+        invalidate: flags.has(b'I') && !expires,
+        ttl: invalidate_ttl.flatten(),
+        keep_item: flags.has(b'x') && !expires,
+    };
+    match cache.meta_delete(key, opts) {
+        Ok(_) | Err(MetaError::NotFound) if flags.has(b'q') => Ok(None),
+        Ok(_) => {
+            let mut out = b"HD".to_vec();
+            return_flags(&mut out, flags, raw_key, None);
+            Ok(Some(out))
+        }
+        Err(e) => Ok(Some(error_response(flags, raw_key, e))),
+    }
+}
+
+fn arithmetic(cache: &Cache<Vec<u8>, Bytes>, raw_key: &[u8], flags: &MetaFlags) -> Response {
+    let key = decode_key(raw_key, flags)?;
+    let incr = match flags.token(b'M').and_then(|token| token.first()) {
+        None | Some(b'I' | b'i' | b'+') => true,
+        Some(b'D' | b'd' | b'-') => false,
+        Some(_) => return Err("invalid mode for ma"),
+    };
+    let touch_ttl = ttl(flags, b'T')?;
+    let vivify_ttl = ttl(flags, b'N')?;
+    let opts = MetaArithmeticOptions {
+        incr,
+        delta: number(flags, b'D')?.unwrap_or(1),
+        compare_cas: number(flags, b'C')?,
+        new_cas: number(flags, b'E')?,
+        vivify_ttl: vivify_ttl.flatten(),
+        initial: number(flags, b'J')?.unwrap_or(0),
+        touch_ttl: touch_ttl.flatten(),
+    };
+    let initial = opts.initial;
+    let result = match cache.meta_arithmetic(key.to_vec(), opts) {
+        Ok(item) if touch_ttl == Some(None) => {
+            cache.remove(&*key);
+            Ok(item)
+        }
+        // the counter would have been created expired
+        Err(MetaError::NotFound) if vivify_ttl == Some(None) => {
+            Ok(expired_item(Bytes::from(initial.to_string()), None))
+        }
+        result => result,
+    };
+    match result {
+        Ok(item) if flags.has(b'v') => Ok(Some(value_response(flags, raw_key, &item, true))),
+        Ok(_) | Err(MetaError::NotFound) if flags.has(b'q') => Ok(None),
+        Ok(item) => Ok(Some(value_response(flags, raw_key, &item, false))),
+        Err(MetaError::NonNumeric) => Err("cannot increment or decrement non-numeric value"),
+        Err(e) => Ok(Some(error_response(flags, raw_key, e))),
+    }
+}
+
+fn debug(cache: &Cache<Vec<u8>, Bytes>, raw_key: &[u8], flags: &MetaFlags) -> Response {
+    let key = decode_key(raw_key, flags)?;
+    match cache.meta_debug(&*key) {
+        Some(item) => {
+            let exp = item.ttl.map_or(-1, |ttl| ttl as i64);
+            let fetch = if item.fetched { "yes" } else { "no" };
+            let mut out = b"ME ".to_vec();
+            out.extend_from_slice(raw_key);
+            out.extend_from_slice(
+                format!(
+                    " exp={} la={} cas={} fetch={} cls=1 size={}",
+                    exp,
+                    item.last_access,
+                    item.cas,
+                    fetch,
+                    item.value.len()
+                )
+                .as_bytes(),
+            );
+            Ok(Some(out))
+        }
+        None => Ok(Some(b"EN".to_vec())),
+    }
+}
+
+fn respond(response: Response) -> Option<Vec<u8>> {
+    response.unwrap_or_else(|e| Some(format!("CLIENT_ERROR {}", e).into_bytes()))
+}
+
+/// Keys given with the b flag are base64 encoded so they can hold binary data.
+/// The key as stored, only base64 encoded keys need to be copied.
+fn decode_key<'a>(raw_key: &'a [u8], flags: &MetaFlags) -> Result<Cow<'a, [u8]>, &'static str> {
+    if flags.has(b'b') {
+        base64::decode(raw_key)
+            .map(Cow::Owned)
+            .map_err(|_| "error decoding key")
+    } else {
+        Ok(Cow::Borrowed(raw_key))
+    }
+}
+
+fn number<T: FromStr>(flags: &MetaFlags, flag: u8) -> Result<Option<T>, &'static str> {
+    flags
+        .number(flag)
+        .map_err(|_| "bad token in command line format")
+}
+
+/// A ttl flag as seconds from now, like the exptimes of the text protocol. `Some(None)` if the
+/// item expires at once: the token is -1 or a unix time which has passed.
+fn ttl(flags: &MetaFlags, flag: u8) -> Result<Option<Option<u32>>, &'static str> {
+    match number::<i64>(flags, flag)? {
+        None => Ok(None),
+        Some(ttl) if ttl < 0 => Ok(Some(None)),
+        Some(ttl) => u32::try_from(ttl)
+            .map(|ttl| Some(relative_exptime(ttl)))
+            .map_err(|_| "bad token in command line format"),
+    }
+}
+
+/// Stands for an item which expired as soon as the command made it.
+fn expired_item(value: Bytes, win: Option<bool>) -> MetaItem<Bytes> {
+    MetaItem {
+        value,
+        flag: 0,
+        cas: 0,
+        ttl: Some(0),
+        last_access: 0,
+        fetched: false,
+        stale: false,
+        win,
+    }
+}
+
+/// `VA <size> <flags>*` followed by the data block if the value is asked for, `HD <flags>*`
+/// otherwise.
+fn value_response(
+    flags: &MetaFlags,
+    raw_key: &[u8],
+    item: &MetaItem<Bytes>,
+    with_value: bool,
+) -> Vec<u8> {
+    let mut out = if with_value {
+        format!("VA {}", item.value.len()).into_bytes()
+    } else {
+        b"HD".to_vec()
+    };
+    return_flags(&mut out, flags, raw_key, Some(item));
+    if with_value {
+        out.extend_from_slice(b"\r\n");
+        out.extend_from_slice(&item.value);
+    }
+    out
+}
+
+fn error_response(flags: &MetaFlags, raw_key: &[u8], e: MetaError) -> Vec<u8> {
+    let mut out = match e {
+        MetaError::NotStored => b"NS".to_vec(),
+        MetaError::Exists => b"EX".to_vec(),
+        MetaError::NotFound => b"NF".to_vec(),
+        MetaError::NonNumeric => b"CLIENT_ERROR non-numeric value".to_vec(),
+    };
+    return_flags(&mut out, flags, raw_key, None);
+    out
+}
+
+/// Appends the return flags asked for by the client, in the order it asked for them, followed
+/// by the W (won the recache), Z (recache already won) and X (stale) flags.
+fn return_flags(
+    out: &mut Vec<u8>,
+    flags: &MetaFlags,
+    raw_key: &[u8],
+    item: Option<&MetaItem<Bytes>>,
+) {
+    for f in &flags.0 {
+        let flag = match (f.flag, item) {
+            (b'O', _) => {
+                out.extend_from_slice(b" O");
+                out.extend_from_slice(&f.token);
+                continue;
+            }
+            (b'k', _) => {
+                out.extend_from_slice(b" k");
+                out.extend_from_slice(raw_key);
+                continue;
+            }
+            (b'b', _) if flags.has(b'k') => " b".to_string(),
+            (b'c', Some(item)) => format!(" c{}", item.cas),
+            (b'f', Some(item)) => format!(" f{}", item.flag),
+            (b'h', Some(item)) => format!(" h{}", item.fetched as u8),
+            (b'l', Some(item)) => format!(" l{}", item.last_access),
+            (b's', Some(item)) => format!(" s{}", item.value.len()),
+            (b't', Some(item)) => format!(" t{}", item.ttl.map_or(-1, |ttl| ttl as i64)),
+            _ => continue,
+        };
+        out.extend_from_slice(flag.as_bytes());
+    }
+    if let Some(item) = item {
+        match item.win {
+            Some(true) => out.extend_from_slice(b" W"),
+            Some(false) => out.extend_from_slice(b" Z"),
+            None => {}
+        }
+        if item.stale {
+            out.extend_from_slice(b" X");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::meta::MetaFlag;
+
+    fn cache() -> Cache<Vec<u8>, Bytes> {
+        Cache::builder().without_vacuum_thread().build()
+    }
+
+    /// The flags of a command line, e.g. `"v T30"`.
+    fn flags(line: &str) -> MetaFlags {
+        MetaFlags(
+            line.split_whitespace()
+                .map(|token| MetaFlag {
+                    flag: token.as_bytes()[0],
+                    token: token.as_bytes()[1..].to_vec(),
+                })
+                .collect(),
+        )
+    }
+
+    fn text(response: Option<Vec<u8>>) -> Option<String> {
+        response.map(|response| String::from_utf8(response).unwrap())
+    }
+
+    fn mg(cache: &Cache<Vec<u8>, Bytes>, key: &str, line: &str) -> Option<String> {
+        text(meta_get(cache, key.as_bytes(), &flags(line)))
+    }
+
+    fn ms(cache: &Cache<Vec<u8>, Bytes>, key: &str, line: &str, data: &str) -> Option<String> {
+        text(meta_set(
+            cache,
+            key.as_bytes(),
+            &flags(line),
+            Bytes::from(data.to_string()),
+        ))
+    }
+
+    fn md(cache: &Cache<Vec<u8>, Bytes>, key: &str, line: &str) -> Option<String> {
+        text(meta_delete(cache, key.as_bytes(), &flags(line)))
+    }
+
+    #[test]
+    fn quiet_mode_only_answers_failures() {
+        let cache = cache();
+        assert_eq!(mg(&cache, "a", "v q"), None);
+        assert_eq!(ms(&cache, "a", "q", "1"), None);
+        assert_eq!(ms(&cache, "a", "q ME", "1").as_deref(), Some("NS"));
+        assert_eq!(mg(&cache, "a", "v q").as_deref(), Some("VA 1\r\n1"));
+        assert_eq!(md(&cache, "a", "q"), None);
+        // a missing key is no failure for a delete
+        assert_eq!(md(&cache, "a", "q"), None);
+        assert_eq!(md(&cache, "a", "").as_deref(), Some("NF"));
+    }
+
+    #[test]
+    fn returns_the_key_and_opaque_in_the_order_asked() {
+        let cache = cache();
+        assert_eq!(ms(&cache, "a", "Oxy k", "1").as_deref(), Some("HD Oxy ka"));
+        assert_eq!(
+            mg(&cache, "a", "k v f Oz").as_deref(),
+            Some("VA 1 ka f0 Oz\r\n1")
+        );
+        assert_eq!(mg(&cache, "b", "k Oz").as_deref(), Some("EN"));
+        assert_eq!(md(&cache, "b", "Oz k").as_deref(), Some("NF Oz kb"));
+    }
+
+    #[test]
+    fn one_client_wins_the_recache_of_a_stale_item() {
+        let cache = cache();
+        ms(&cache, "a", "", "1");
+        assert_eq!(md(&cache, "a", "I T30").as_deref(), Some("HD"));
+        assert_eq!(mg(&cache, "a", "v").as_deref(), Some("VA 1 W X\r\n1"));
+        assert_eq!(mg(&cache, "a", "v").as_deref(), Some("VA 1 Z X\r\n1"));
+        // the winner stores the new value, which is fresh again
+        assert_eq!(ms(&cache, "a", "", "2").as_deref(), Some("HD"));
+        assert_eq!(mg(&cache, "a", "v").as_deref(), Some("VA 1\r\n2"));
+    }
+
+    #[test]
+    fn one_client_wins_the_fill_of_a_missing_item() {
+        let cache = cache();
+        assert_eq!(mg(&cache, "a", "v N30").as_deref(), Some("VA 0 W\r\n"));
+        assert_eq!(mg(&cache, "a", "v N30").as_deref(), Some("VA 0 Z\r\n"));
+    }
+
+    #[test]
+    fn a_ttl_of_minus_one_expires_the_item_at_once() {
+        let cache = cache();
+        assert_eq!(ms(&cache, "a", "T-1", "1").as_deref(), Some("HD"));
+        assert_eq!(mg(&cache, "a", "v").as_deref(), Some("EN"));
+
+        ms(&cache, "b", "", "1");
+        // the item is still returned by the get which expires it
+        assert_eq!(mg(&cache, "b", "v T-1").as_deref(), Some("VA 1\r\n1"));
+        assert_eq!(mg(&cache, "b", "v").as_deref(), Some("EN"));
+
+        assert_eq!(mg(&cache, "c", "v N-1").as_deref(), Some("VA 0 W\r\n"));
+        assert_eq!(mg(&cache, "c", "v").as_deref(), Some("EN"));
+
+        assert_eq!(
+            mg(&cache, "d", "T-2x").as_deref(),
+            Some("CLIENT_ERROR bad token in command line format")
+        );
+    }
+}
@@ -1,28 +1,48 @@
+use crate::append_log::AppendLog;
+use crate::connection::{Connection, MAX_FRAME_SIZE};
+use crate::memcache_binary;
+use crate::memcache_meta;
 use crate::metrics::METRIC_REQUEST_DURATION_MEMC;
 use crate::parser::ascii::parse_ascii_cmd;
-use crate::parser::Cmd;
-use kv_cache::Cache;
+use crate::parser::binary::MAGIC_REQUEST;
+use crate::parser::{Cmd, StatsGroup, StoreMode};
+use crate::stats::{self, ServerStats};
+use bytes::Bytes;
+use kv_cache::{Cache, CasResult, CounterError, Item, MetaDeleteOptions, MetaError};
 use log::{debug, info, trace};
 use nom::AsBytes;
-use std::time::SystemTime;
-use tokio::io::Error;
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io;
 use tokio::net::{TcpListener, TcpStream};
 
+pub(crate) const VERSION: &str = env!("CARGO_PKG_VERSION");
+const PORT: u16 = 6001;
+/// Exptimes above 30 days are unix times rather than seconds from now, as in memcached.
+const MAX_RELATIVE_EXPTIME: u32 = 60 * 60 * 24 * 30;
+
 pub struct MemcacheServer {
-    cache: Cache<Vec<u8>, Vec<u8>>,
+    cache: Cache<Vec<u8>, Bytes>,
+    stats: Arc<ServerStats>,
+    /// The log the changes to the cache are appended to, if any.
+    append_log: Option<AppendLog>,
 }
 
 impl MemcacheServer {
-    pub fn new(cache: Cache<Vec<u8>, Vec<u8>>) -> Self {
-        MemcacheServer { cache }
+    pub fn new(cache: Cache<Vec<u8>, Bytes>, append_log: Option<AppendLog>) -> Self {
+        MemcacheServer {
+            cache,
+            stats: Arc::new(ServerStats::new()),
+            append_log,
+        }
     }
 
     pub async fn serve(&self) -> () {
         // Bind the listener to the address
-        let addr = "0.0.0.0:6001";
+        let addr = format!("0.0.0.0:{}", PORT);
         info!("Memcache server listening on http://{}, use `nc -c localhost 6001` to connect and test", addr);
-        let listener = TcpListener::bind(addr).await.unwrap();
+        let listener = TcpListener::bind(&addr).await.unwrap();
 
         let memcached = async {
             loop {
@@ -39,244 +59,495 @@ impl MemcacheServer {
     async fn process(&self, socket: TcpStream) {
         // Write data in the background
         let cache = self.cache.clone();
+        let stats = self.stats.clone();
+        let log = self.append_log.clone();
+        ServerStats::incr(&stats.curr_connections, 1);
+        ServerStats::incr(&stats.total_connections, 1);
         tokio::spawn(async move {
             let mut connection = Connection::new(socket);
-            loop {
-                trace!("process loop");
-                let mut start_time = SystemTime::now();
-                let cmd_raw = connection
-                    .read_frame(|frame| {
-                        start_time = SystemTime::now();
-                        parse_ascii_cmd(frame)
-                            .map(|r| r.1)
-                            .map_err(|e| e.to_string())
-                    })
-                    .await;
-                trace!("process loop - got cmd");
-                match cmd_raw {
-                    Ok(parse_result) => {
-                        match parse_result {
-                            Ok(cmd) => {
-                                trace!("cmd: {:?}", cmd);
-                                match cmd {
-                                    Cmd::CmdSet {
-                                        key,
-                                        flag,
-                                        ttl,
-                                        len,
-                                        noreply,
-                                    } => {
-                                        // reading value
-                                        trace!(
-                                            "cmd set key: {}",
-                                            std::str::from_utf8(key.as_bytes()).unwrap()
-                                        );
-                                        let value =
-                                            connection.read_frame(|frame| frame.to_vec()).await;
-                                        match value {
-                                            Ok(mut v) => {
-                                                trace!("GOT set value: {:?}", v);
-                                                if v.len() != (len + 2) as usize {
-                                                    if let Err(_e) = connection
-                                                        .write_frame(
-                                                            "CLIENT_ERROR bad data chunk"
-                                                                .as_bytes(),
-                                                        )
-                                                        .await
-                                                    {
-                                                        break;
-                                                    }
-                                                    continue; // value input after set is invalid
-                                                }
-                                                v.pop();
-                                                v.pop();
-                                                cache.insert_with_ttl(key, v, ttl, flag);
-                                                if !noreply.unwrap_or(false) {
-                                                    if let Err(_e) = connection
-                                                        .write_frame("STORED".as_bytes())
-                                                        .await
-                                                    {
-                                                        break;
-                                                    }
-                                                }
-                                                let duration = SystemTime::now()
-                                                    .duration_since(start_time)
-                                                    .unwrap();
-                                                METRIC_REQUEST_DURATION_MEMC
-                                                    .with_label_values(&["set"])
-                                                    .observe(duration.as_secs_f64());
-                                            }
-                                            // read_frame error when reading a value
-                                            Err(e) => {
-                                                debug!(
-                                                    "read_frame error when reading value: {}",
-                                                    e.to_string()
-                                                );
-                                                break;
-                                            }
-                                        }
-                                    }
-                                    Cmd::CmdGet { mut key } => {
-                                        trace!(
-                                            "cmd set key: {}",
-                                            std::str::from_utf8(key.as_bytes()).unwrap()
-                                        );
-                                        match cache.get(&key) {
-                                            Some(value) => {
-                                                let mut len = value.len().to_string().into_bytes();
-                                                let mut flag =
-                                                    value.get_flag().to_string().into_bytes();
-                                                let mut value_header =
-                                                    Vec::<u8>::with_capacity(6 + key.len() + 100);
-                                                value_header.append(&mut b"VALUE ".to_vec());
-                                                value_header.append(&mut key);
-                                                value_header.append(&mut b" ".to_vec());
-                                                value_header.append(&mut flag);
-                                                value_header.append(&mut b" ".to_vec());
-                                                value_header.append(&mut len);
-                                                if let Err(_e) = connection
-                                                    .write_frame(value_header.as_bytes())
-                                                    .await
-                                                    .and(
-                                                        connection
-                                                            .write_frame(value.as_bytes())
-                                                            .await,
-                                                    )
-                                                {
-                                                    break;
-                                                }
-                                            }
-                                            None => (),
-                                        };
-                                        if let Err(_e) =
-                                            connection.write_frame("END".as_bytes()).await
-                                        {
-                                            break;
-                                        }
-                                        let duration =
-                                            SystemTime::now().duration_since(start_time).unwrap();
-                                        METRIC_REQUEST_DURATION_MEMC
-                                            .with_label_values(&["get"])
-                                            .observe(duration.as_secs_f64());
-                                    }
-                                    Cmd::CmdVersion => {
-                                        if let Err(_e) =
-                                            connection.write_frame("VERSION 0.1.0".as_bytes()).await
-                                        {
-                                            break;
-                                        }
-                                    }
-                                }
-                            }
-                            // parse error
-                            Err(e) => {
-                                debug!("parse error: {}", e.to_string());
-                                if let Err(write_e) =
-                                    // Override error as "ERROR" for cmd parsing error
-                                    connection.write_frame(b"ERROR").await
-                                {
-                                    info!("write_frame error: {}", write_e.to_string());
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        debug!("read_frame error when reading cmd: {}", e.to_string());
-                        break;
-                    }
+            // Binary clients are told apart by the magic byte their first request starts with,
+            // ascii commands never start with it.
+            let result = match connection.peek_byte().await {
+                Ok(MAGIC_REQUEST) => {
+                    memcache_binary::process(&mut connection, &cache, &stats, log.as_ref()).await
                 }
+                Ok(_) => process_ascii(&mut connection, &cache, &stats, log.as_ref()).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                debug!("connection closed: {}", e);
             }
+            stats.curr_connections.fetch_sub(1, Ordering::Relaxed);
         });
     }
 }
 
-struct Connection {
-    stream: TcpStream,
-    buffer: Vec<u8>,
-    cursor: usize,
-    head: usize,
-}
+/// Serves ascii commands until the connection breaks.
+async fn process_ascii(
+    connection: &mut Connection,
+    cache: &Cache<Vec<u8>, Bytes>,
+    stats: &ServerStats,
+    log: Option<&AppendLog>,
+) -> io::Result<()> {
+    loop {
+        trace!("process loop");
+        let frame_len = connection.fill_frame().await?;
+        let start_time = SystemTime::now();
+        trace!("process loop - got cmd");
+        // the command borrows its keys from the buffer, the rest of its data block is read from
+        // the stream past the buffer
+        let (buffered, mut rest) = connection.split();
+        let cmd = match parse_ascii_cmd(&buffered[..frame_len]) {
+            Ok((_, cmd)) => cmd,
+            // parse error
+            Err(e) => {
+                debug!("parse error: {}", e);
+                connection.consume(frame_len);
+                // Override error as "ERROR" for cmd parsing error
+                connection.write_frame(b"ERROR").await?;
+                continue;
+            }
+        };
+        let mut consumed = frame_len;
+        let block = match cmd.data_len().map(|len| len as usize + 2) {
+            Some(block_len) if frame_len + block_len > MAX_FRAME_SIZE => {
+                // the data block is not read, so there is no telling where the next command starts
+                connection
+                    .write_frame(b"SERVER_ERROR object too large for cache")
+                    .await?;
+                return Err(io::Error::from(io::ErrorKind::FileTooLarge));
+            }
+            Some(block_len) => {
+                let start = &buffered[frame_len..];
+                let start = &start[..start.len().min(block_len)];
+                let mut block = Vec::with_capacity(block_len);
+                block.extend_from_slice(start);
+                block.resize(block_len, 0);
+                rest.read_exact(&mut block[start.len()..]).await?;
+                consumed += start.len();
+                Some(block)
+            }
+            None => None,
+        };
 
-impl Connection {
-    pub fn new(stream: TcpStream) -> Connection {
-        Connection {
-            stream,
-            // Allocate the buffer with 1kb of capacity. - 1024
-            buffer: vec![0; 1024],
-            cursor: 0,
-            head: 0,
+        let result = handle_cmd(cache, stats, cmd, block);
+        let synced = log.map(AppendLog::synced);
+        connection.consume(consumed);
+        // the changes of the command are on disk before the client hears of them
+        if let Some(synced) = synced {
+            synced.await;
+        }
+        match result {
+            Some((name, noreply, reply)) => {
+                if !noreply {
+                    write_reply(connection, reply).await?;
+                }
+                observe_duration(name, start_time);
+            }
+            // value input after set is invalid
+            None => {
+                connection
+                    .write_frame(b"CLIENT_ERROR bad data chunk")
+                    .await?
+            }
         }
     }
+}
 
-    pub async fn read_frame<F, T>(&mut self, mut func: F) -> io::Result<T>
-    where
-        F: FnMut(&[u8]) -> T,
-    {
-        loop {
-            trace!("read_frame_loop");
-            if self.cursor > 0 {
-                let start = self.head;
-                let end = self.cursor - 1;
+/// The outcome of a command, written back in whichever protocol the command came in.
+pub(crate) enum Reply {
+    /// The cas unique of the stored item, which only the binary protocol returns.
+    Stored(u64),
+    NotStored,
+    Exists,
+    NotFound,
+    Deleted,
+    Touched,
+    Ok,
+    Reset,
+    /// The hits of a retrieval command with the key they were found under, `cas` tells whether
+    /// the command asked for the cas unique.
+    Values {
+        items: Vec<(Vec<u8>, Item<Bytes>)>,
+        cas: bool,
+    },
+    /// The new value of a counter and the cas unique of its item.
+    Counter(u64, u64),
+    NonNumeric,
+    Stats(Vec<String>),
+    Version,
+    /// A response of a meta command, which only exists in the text protocol so it comes
+    /// encoded already. `None` when quiet mode suppresses it.
+    Meta(Option<Vec<u8>>),
+}
 
-                for i in start..end {
-                    if &self.buffer[i..i + 2] == b"\r\n" {
-                        // found \r\n, call func to parse frame for cmd or value
-                        let result = func(&self.buffer[self.head..i + 2]);
-                        if i + 1 == end {
-                            // no extra behind cursor
-                            self.cursor = 0;
-                            self.head = 0;
-                        } else {
-                            // has extra stuff behind i+2
-                            self.head = i + 2;
-                        }
-                        return Ok(result);
+/// Executes a command against the cache, `data` is the data block of storage commands.
+pub(crate) fn execute(
+    cache: &Cache<Vec<u8>, Bytes>,
+    stats: &ServerStats,
+    cmd: Cmd<'_>,
+    data: Bytes,
+) -> Reply {
+    // gets and gats also return the cas unique
+    let with_cas = matches!(cmd, Cmd::CmdGets { .. } | Cmd::CmdGats { .. });
+    match cmd {
+        Cmd::CmdStore {
+            mode,
+            key,
+            flag,
+            ttl,
+            ..
+        } => {
+            trace!("cmd {} key: {}", mode.name(), String::from_utf8_lossy(key));
+            ServerStats::incr(&stats.cmd_set, 1);
+            let exptime = relative_exptime(ttl);
+            let ttl = exptime.unwrap_or(0);
+            let reply = match mode {
+                StoreMode::Set => Reply::Stored(cache.set(key.to_vec(), data, ttl, flag)),
+                StoreMode::Add => stored_reply(cache.add(key.to_vec(), data, ttl, flag)),
+                StoreMode::Replace => stored_reply(cache.replace(key.to_vec(), data, ttl, flag)),
+                StoreMode::Append => stored_reply(cache.append(key, &data)),
+                StoreMode::Prepend => stored_reply(cache.prepend(key, &data)),
+                StoreMode::Cas(unique) => {
+                    match cache.compare_and_swap(key.to_vec(), data, ttl, flag, unique) {
+                        CasResult::Stored(cas) => Reply::Stored(cas),
+                        CasResult::Exists => Reply::Exists,
+                        CasResult::NotFound => Reply::NotFound,
                     }
                 }
-                // incomplete - maybe error or?
+            };
+            // the exptime has passed: memcached stores the item expired, so it is gone right
+            // away. Appends keep the exptime of the item.
+            let keeps_exptime = matches!(mode, StoreMode::Append | StoreMode::Prepend);
+            if exptime.is_none() && !keeps_exptime && matches!(reply, Reply::Stored(_)) {
+                cache.remove(key);
             }
-
-            // Ensure the buffer has capacity
-            if self.buffer.len() == self.cursor {
-                let new_len = self.cursor * 2;
-                if new_len > 4096 * 1024 {
-                    // 4096 = Max 4kb input size
-                    self.cursor = 0;
-                    return Err(Error::from(io::ErrorKind::FileTooLarge));
+            reply
+        }
+        Cmd::CmdGet { keys } | Cmd::CmdGets { keys } => {
+            trace!("cmd get keys: {}", keys.len());
+            ServerStats::incr(&stats.cmd_get, keys.len() as u64);
+            let values = cache.get_many(&keys);
+            Reply::Values {
+                items: hits(keys.into_iter().zip(values)),
+                cas: with_cas,
+            }
+        }
+        Cmd::CmdGat { ttl, keys } | Cmd::CmdGats { ttl, keys } => {
+            ServerStats::incr(&stats.cmd_touch, keys.len() as u64);
+            let values: Vec<_> = match relative_exptime(ttl) {
+                Some(ttl) => keys
+                    .iter()
+                    .map(|key| cache.get_and_touch(*key, ttl))
+                    .collect(),
+                // the exptime has passed: memcached still returns the items, they are gone after
+                None => keys
+                    .iter()
+                    .map(|key| {
+                        let item = cache.get(*key);
+                        cache.remove(*key);
+                        item
+                    })
+                    .collect(),
+            };
+            Reply::Values {
+                items: hits(keys.into_iter().zip(values)),
+                cas: with_cas,
+            }
+        }
+        Cmd::CmdTouch { key, ttl, .. } => {
+            ServerStats::incr(&stats.cmd_touch, 1);
+            let touched = match relative_exptime(ttl) {
+                Some(ttl) => cache.touch(key, ttl),
+                None => cache.remove(key).is_some(),
+            };
+            if touched {
+                Reply::Touched
+            } else {
+                Reply::NotFound
+            }
+        }
+        Cmd::CmdDelete { key, cas, .. } => {
+            trace!("cmd delete key: {}", String::from_utf8_lossy(key));
+            match cas {
+                Some(cas) => {
+                    let opts = MetaDeleteOptions {
+                        compare_cas: Some(cas),
+                        ..Default::default()
+                    };
+                    match cache.meta_delete(key.to_vec(), opts) {
+                        Ok(()) => Reply::Deleted,
+                        Err(MetaError::Exists) => Reply::Exists,
+                        Err(_) => Reply::NotFound,
+                    }
+                }
+                None => match cache.remove(key) {
+                    Some(_) => Reply::Deleted,
+                    None => Reply::NotFound,
+                },
+            }
+        }
+        Cmd::CmdIncr { key, delta, .. } => counter_reply(cache.incr(key, delta)),
+        Cmd::CmdDecr { key, delta, .. } => counter_reply(cache.decr(key, delta)),
+        Cmd::CmdFlushAll { delay, .. } => {
+            ServerStats::incr(&stats.cmd_flush, 1);
+            match delay.and_then(relative_exptime) {
+                Some(delay) if delay > 0 => {
+                    cache.invalidate_before(SystemTime::now() + Duration::from_secs(delay as u64))
                 }
-                trace!("read_frame_loop - buffer resize {}", new_len);
-                // Grow the buffer
-                self.buffer.resize(new_len, 0);
+                _ => cache.clear(),
+            }
+            Reply::Ok
+        }
+        Cmd::CmdMetaGet { key, flags } => {
+            ServerStats::incr(&stats.cmd_get, 1);
+            Reply::Meta(memcache_meta::meta_get(cache, key, &flags))
+        }
+        Cmd::CmdMetaSet { key, flags, .. } => {
+            ServerStats::incr(&stats.cmd_set, 1);
+            Reply::Meta(memcache_meta::meta_set(cache, key, &flags, data))
+        }
+        Cmd::CmdMetaDelete { key, flags } => {
+            Reply::Meta(memcache_meta::meta_delete(cache, key, &flags))
+        }
+        Cmd::CmdMetaArithmetic { key, flags } => {
+            Reply::Meta(memcache_meta::meta_arithmetic(cache, key, &flags))
+        }
+        Cmd::CmdMetaDebug { key, flags } => {
+            Reply::Meta(memcache_meta::meta_debug(cache, key, &flags))
+        }
+        Cmd::CmdMetaNoop => Reply::Meta(Some(b"MN".to_vec())),
+        Cmd::CmdStats { group } => match group {
+            StatsGroup::General => Reply::Stats(stats.general(cache, VERSION)),
+            StatsGroup::Settings => Reply::Stats(vec![
+                stats::stat("tcpport", PORT),
+                stats::stat("item_size_max", MAX_FRAME_SIZE),
+                stats::stat("cas_enabled", "yes"),
+                stats::stat("flush_enabled", "yes"),
+            ]),
+            StatsGroup::Items => Reply::Stats(stats::items(cache)),
+            StatsGroup::Sizes => Reply::Stats(stats::sizes(cache)),
+            StatsGroup::Reset => {
+                stats.reset();
+                cache.reset_stats();
+                Reply::Reset
             }
+        },
+        Cmd::CmdVersion => Reply::Version,
+    }
+}
 
-            // Read into the buffer, tracking the number
-            // of bytes read
-            let n = self.stream.read(&mut self.buffer[self.cursor..]).await?;
+/// Executes an ascii command, `block` is the data block of storage commands with its `\r\n`.
+///
+/// Returns the command name, whether it asked for no reply and the reply, `None` if the data block
+/// is not terminated by `\r\n` where its length says it ends.
+fn handle_cmd(
+    cache: &Cache<Vec<u8>, Bytes>,
+    stats: &ServerStats,
+    cmd: Cmd<'_>,
+    block: Option<Vec<u8>>,
+) -> Option<(&'static str, bool, Reply)> {
+    trace!("cmd: {:?}", cmd);
+    let data = match block {
+        Some(mut block) => {
+            if !block.ends_with(b"\r\n") {
+                return None;
+            }
+            block.truncate(block.len() - 2);
+            trace!("GOT {} value: {:?}", cmd.name(), block);
+            Bytes::from(block)
+        }
+        None => Bytes::new(),
+    };
+    let name = cmd.name();
+    let noreply = cmd.noreply();
+    Some((name, noreply, execute(cache, stats, cmd, data)))
+}
 
-            if 0 == n {
-                if self.cursor == 0 {
-                    return Err(Error::from(io::ErrorKind::ConnectionReset));
-                } else {
-                    // Maybe use a different error for this case?
-                    self.cursor = 0;
-                    return Err(Error::from(io::ErrorKind::ConnectionReset));
-                }
-            } else {
-                // Update our cursor
-                self.cursor += n;
+/// Writes a reply in the ascii protocol.
+async fn write_reply(connection: &mut Connection, reply: Reply) -> io::Result<()> {
+    match reply {
+        Reply::Stored(_) => connection.write_frame(b"STORED").await,
+        Reply::NotStored => connection.write_frame(b"NOT_STORED").await,
+        Reply::Exists => connection.write_frame(b"EXISTS").await,
+        Reply::NotFound => connection.write_frame(b"NOT_FOUND").await,
+        Reply::Deleted => connection.write_frame(b"DELETED").await,
+        Reply::Touched => connection.write_frame(b"TOUCHED").await,
+        Reply::Ok => connection.write_frame(b"OK").await,
+        Reply::Reset => connection.write_frame(b"RESET").await,
+        Reply::Values { items, cas } => {
+            for (key, value) in items {
+                let cas = if cas { Some(value.get_cas()) } else { None };
+                write_value(connection, &key, &value, value.get_flag(), cas).await?;
             }
-            trace!("read_frame_loop - end");
+            connection.write_frame(b"END").await
+        }
+        Reply::Counter(n, _) => connection.write_frame(n.to_string().as_bytes()).await,
+        Reply::NonNumeric => {
+            connection
+                .write_frame(b"CLIENT_ERROR cannot increment or decrement non-numeric value")
+                .await
+        }
+        Reply::Stats(lines) => {
+            let mut response = String::new();
+            for line in lines {
+                response.push_str(&line);
+                response.push_str("\r\n");
+            }
+            response.push_str("END");
+            connection.write_frame(response.as_bytes()).await
+        }
+        Reply::Version => {
+            connection
+                .write_frame(format!("VERSION {}", VERSION).as_bytes())
+                .await
         }
+        Reply::Meta(Some(response)) => connection.write_frame(&response).await,
+        Reply::Meta(None) => Ok(()),
     }
+}
+
+/// Keeps the keys that were found, paired with their values.
+fn hits<'a>(
+    values: impl Iterator<Item = (&'a [u8], Option<Item<Bytes>>)>,
+) -> Vec<(Vec<u8>, Item<Bytes>)> {
+    values
+        .filter_map(|(key, value)| value.map(|value| (key.to_vec(), value)))
+        .collect()
+}
+
+/// Turns an exptime or a flush delay into seconds from now, `None` if it is a unix time which has
+/// passed.
+pub(crate) fn relative_exptime(exptime: u32) -> Option<u32> {
+    if exptime <= MAX_RELATIVE_EXPTIME {
+        return Some(exptime);
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    (exptime as u64)
+        .checked_sub(now)
+        .filter(|delay| *delay > 0)
+        .map(|delay| delay as u32)
+}
 
-    async fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
-        trace!("write_frame - '{}'", std::str::from_utf8(frame).unwrap());
-        self.stream.write_all(frame).await?;
-        self.stream.write_all(b"\r\n").await?;
+fn stored_reply(stored: Option<u64>) -> Reply {
+    match stored {
+        Some(cas) => Reply::Stored(cas),
+        None => Reply::NotStored,
+    }
+}
 
-        self.stream.flush().await?;
+fn counter_reply(result: Result<(u64, u64), CounterError>) -> Reply {
+    match result {
+        Ok((n, cas)) => Reply::Counter(n, cas),
+        Err(CounterError::NotFound) => Reply::NotFound,
+        Err(CounterError::NonNumeric) => Reply::NonNumeric,
+    }
+}
+
+/// Writes a `VALUE <key> <flags> <bytes> [<cas unique>]` line followed by the data block.
+async fn write_value(
+    connection: &mut Connection,
+    key: &[u8],
+    value: &[u8],
+    flag: u32,
+    cas: Option<u64>,
+) -> io::Result<()> {
+    let mut value_header = Vec::<u8>::with_capacity(6 + key.len() + 100);
+    value_header.extend_from_slice(b"VALUE ");
+    value_header.extend_from_slice(key);
+    value_header.extend_from_slice(format!(" {} {}", flag, value.len()).as_bytes());
+    if let Some(cas) = cas {
+        value_header.extend_from_slice(format!(" {}", cas).as_bytes());
+    }
+    connection.write_frame(value_header.as_bytes()).await?;
+    connection.write_frame(value).await
+}
+
+pub(crate) fn observe_duration(method: &str, start_time: SystemTime) {
+    let duration = SystemTime::now().duration_since(start_time).unwrap();
+    METRIC_REQUEST_DURATION_MEMC
+        .with_label_values(&[method])
+        .observe(duration.as_secs_f64());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Serves ascii commands on a fresh cache and returns the client end of the connection.
+    async fn serve_ascii() -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        client.set_nodelay(true).unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move {
+            let cache = Cache::builder().without_vacuum_thread().build();
+            let mut connection = Connection::new(socket);
+            let _ = process_ascii(&mut connection, &cache, &ServerStats::new(), None).await;
+        });
+        client
+    }
+
+    /// Reads until the reply ends with `end`.
+    async fn read_reply(client: &mut TcpStream, end: &[u8]) -> Vec<u8> {
+        let mut reply = Vec::new();
+        while !reply.ends_with(end) {
+            let mut buf = [0; 1024];
+            let read = tokio::time::timeout(Duration::from_secs(5), client.read(&mut buf));
+            let n = read
+                .await
+                .unwrap_or_else(|_| panic!("stuck after {:?}", String::from_utf8_lossy(&reply)))
+                .unwrap();
+            assert!(n > 0, "closed after {:?}", String::from_utf8_lossy(&reply));
+            reply.extend_from_slice(&buf[..n]);
+        }
+        reply
+    }
+
+    #[tokio::test]
+    async fn stores_and_returns_a_value_holding_line_ends() {
+        let mut client = serve_ascii().await;
+        client
+            .write_all(b"set k 3 0 8\r\n\r\na\r\nb\r\n\r\nget k\r\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            read_reply(&mut client, b"END\r\n").await,
+            b"STORED\r\nVALUE k 3 8\r\n\r\na\r\nb\r\n\r\nEND\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn stores_a_data_block_split_across_reads() {
+        let mut client = serve_ascii().await;
+        let pieces: [&[u8]; 4] = [b"set k 0 0 7\r\nab", b"c\r", b"\nd", b"e\r\nget k\r\n"];
+        for piece in pieces {
+            client.write_all(piece).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            read_reply(&mut client, b"END\r\n").await,
+            b"STORED\r\nVALUE k 0 7\r\nabc\r\nde\r\nEND\r\n"
+        );
+    }
 
-        Ok(())
+    #[tokio::test]
+    async fn rejects_a_data_block_longer_than_announced() {
+        let mut client = serve_ascii().await;
+        client
+            .write_all(b"set k 0 0 2\r\nabcd\r\nget k\r\n")
+            .await
+            .unwrap();
+        // the block is cut at the announced length, the rest of it is read as a command
+        assert_eq!(
+            read_reply(&mut client, b"END\r\n").await,
+            b"CLIENT_ERROR bad data chunk\r\nERROR\r\nEND\r\n"
+        );
     }
 }
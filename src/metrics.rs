@@ -25,4 +25,49 @@ lazy_static! {
         "cache_size",
         "Size of the cache"
         ).unwrap();
+
+    pub static ref METRIC_CACHE_BYTES: Gauge = register_gauge!(
+        "cache_bytes",
+        "Approximate number of bytes taken by the items of the cache"
+        ).unwrap();
+
+    pub static ref METRIC_CACHE_HITS: Gauge = register_gauge!(
+        "cache_hits",
+        "Number of lookups which found a live item"
+        ).unwrap();
+
+    pub static ref METRIC_CACHE_MISSES: Gauge = register_gauge!(
+        "cache_misses",
+        "Number of lookups which found nothing or an expired item"
+        ).unwrap();
+
+    pub static ref METRIC_CACHE_INSERTS: Gauge = register_gauge!(
+        "cache_inserts",
+        "Number of items stored under a key which had none"
+        ).unwrap();
+
+    pub static ref METRIC_CACHE_OVERWRITES: Gauge = register_gauge!(
+        "cache_overwrites",
+        "Number of stores and updates of a live item"
+        ).unwrap();
+
+    pub static ref METRIC_CACHE_EXPIRATIONS: Gauge = register_gauge!(
+        "cache_expirations",
+        "Number of items removed because their ttl had passed"
+        ).unwrap();
+
+    pub static ref METRIC_CACHE_EVICTIONS: Gauge = register_gauge!(
+        "cache_evictions",
+        "Number of live items evicted to free memory"
+        ).unwrap();
+
+    pub static ref METRIC_SWEEP_DURATION: Gauge = register_gauge!(
+        "cache_sweep_duration_seconds",
+        "Duration of the last sweep for expired items in seconds"
+        ).unwrap();
+
+    pub static ref METRIC_SWEEP_RECLAIMED: Gauge = register_gauge!(
+        "cache_sweep_reclaimed",
+        "Number of expired items reclaimed by the sweeps"
+        ).unwrap();
 }
@@ -1,4 +1,5 @@
-use crate::parser::Cmd;
+use crate::parser::meta::parse_meta_cmd;
+use crate::parser::{Cmd, StatsGroup, StoreMode};
 use btoi::btou;
 use log::debug;
 use nom::bytes::streaming::tag_no_case;
@@ -8,7 +9,8 @@ use nom::{
     bytes::streaming::{tag, take_while1, take_while_m_n},
     character::{is_digit, streaming::crlf},
     combinator::{map_res, opt, value},
-    sequence::tuple,
+    multi::many1,
+    sequence::{preceded, tuple},
     IResult,
 };
 
@@ -20,51 +22,118 @@ fn parse_ascii_u32(buf: &[u8]) -> IResult<&[u8], u32> {
     map_res(take_while_m_n(1, 10, is_digit), btou)(buf)
 }
 
-pub(crate) fn parse_ascii_cmd(buf: &[u8]) -> IResult<&[u8], Cmd> {
+fn parse_ascii_u64(buf: &[u8]) -> IResult<&[u8], u64> {
+    map_res(take_while_m_n(1, 20, is_digit), btou)(buf)
+}
+
+pub(crate) fn parse_ascii_cmd(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
     // debug!("Parsing: '{}'", std::str::from_utf8(buf).unwrap());
     if buf.is_empty() {
         debug!("Parsing with empty buf");
         return Err(nom::Err::Error(Error::new(buf, ErrorKind::Eof)));
     }
 
+    // meta commands all start with "m" which no other command does
+    if buf[0] == b'm' {
+        return parse_meta_cmd(buf);
+    }
+
     let (buf, c) = alt((
         value("set", tag_no_case(b"set")),
+        value("add", tag_no_case(b"add")),
+        value("replace", tag_no_case(b"replace")),
+        value("append", tag_no_case(b"append")),
+        value("prepend", tag_no_case(b"prepend")),
+        value("cas", tag_no_case(b"cas")),
+        // "gets" must be tried before its prefix "get"
+        value("gets", tag_no_case(b"gets")),
         value("get", tag_no_case(b"get")),
+        // "gats" must be tried before its prefix "gat"
+        value("gats", tag_no_case(b"gats")),
+        value("gat", tag_no_case(b"gat")),
+        value("touch", tag_no_case(b"touch")),
+        value("delete", tag_no_case(b"delete")),
+        value("incr", tag_no_case(b"incr")),
+        value("decr", tag_no_case(b"decr")),
+        value("flush_all", tag_no_case(b"flush_all")),
+        value("stats", tag_no_case(b"stats")),
         value("version", tag_no_case(b"version")),
     ))(buf)?;
 
     match c {
-        "set" => {
-            let (buf, (_, key, _, flag, _, ttl, _, len, _, noreply, _)) = tuple((
-                // VALUE key flags data_len [cas id]\r\n
-                // data block\r\n
+        "set" => parse_ascii_store(buf, StoreMode::Set),
+        "add" => parse_ascii_store(buf, StoreMode::Add),
+        "replace" => parse_ascii_store(buf, StoreMode::Replace),
+        "append" => parse_ascii_store(buf, StoreMode::Append),
+        "prepend" => parse_ascii_store(buf, StoreMode::Prepend),
+        "cas" => parse_ascii_cas(buf),
+        "gets" => {
+            let (buf, keys) = parse_ascii_keys(buf)?;
+            Ok((buf, Cmd::CmdGets { keys }))
+        }
+        "get" => {
+            // get <key>*\r\n
+            let (buf, keys) = parse_ascii_keys(buf)?;
+            Ok((buf, Cmd::CmdGet { keys }))
+        }
+        "gat" | "gats" => {
+            // gat|gats <exptime> <key>*\r\n
+            let (buf, (_, ttl)) = tuple((tag(" "), parse_ascii_u32))(buf)?;
+            let (buf, keys) = parse_ascii_keys(buf)?;
+            let cmd = if c == "gat" {
+                Cmd::CmdGat { ttl, keys }
+            } else {
+                Cmd::CmdGats { ttl, keys }
+            };
+            Ok((buf, cmd))
+        }
+        "touch" => {
+            // touch <key> <exptime> [noreply]\r\n
+            let (buf, (_, key, _, ttl)) = tuple((
                 tag(" "),
                 take_while1(is_key_char),
                 tag(" "),
-                parse_ascii_u32, // flag
-                tag(" "),
-                parse_ascii_u32, // ttl
-                tag(" "),
-                parse_ascii_u32, // len
-                opt(tag(" ")),
-                opt(alt((value(true, tag_no_case(b"noreply")),))),
-                crlf,
+                parse_ascii_u32,
             ))(buf)?;
+            let (buf, noreply) = parse_ascii_noreply(buf)?;
+            Ok((buf, Cmd::CmdTouch { key, ttl, noreply }))
+        }
+        "delete" => {
+            let (buf, (_, key)) = tuple((tag(" "), take_while1(is_key_char)))(buf)?;
+            let (buf, noreply) = parse_ascii_noreply(buf)?;
             Ok((
                 buf,
-                Cmd::CmdSet {
-                    key: key.to_vec(),
-                    flag,
-                    ttl,
-                    len,
+                Cmd::CmdDelete {
+                    key,
+                    cas: None,
                     noreply,
                 },
             ))
         }
-        "get" => {
-            // get cmd
-            let (buf, (_, key, _)) = tuple((tag(" "), take_while1(is_key_char), crlf))(buf)?;
-            Ok((buf, Cmd::CmdGet { key: key.to_vec() }))
+        "incr" => parse_ascii_counter(buf, true),
+        "decr" => parse_ascii_counter(buf, false),
+        "flush_all" => {
+            // flush_all [delay] [noreply]\r\n
+            let (buf, delay) = opt(preceded(tag(" "), parse_ascii_u32))(buf)?;
+            let (buf, noreply) = parse_ascii_noreply(buf)?;
+            Ok((buf, Cmd::CmdFlushAll { delay, noreply }))
+        }
+        "stats" => {
+            // stats [settings|items|sizes|reset]\r\n
+            let (buf, (group, _)) = tuple((
+                opt(preceded(
+                    tag(" "),
+                    alt((
+                        value(StatsGroup::Settings, tag_no_case(b"settings")),
+                        value(StatsGroup::Items, tag_no_case(b"items")),
+                        value(StatsGroup::Sizes, tag_no_case(b"sizes")),
+                        value(StatsGroup::Reset, tag_no_case(b"reset")),
+                    )),
+                )),
+                crlf,
+            ))(buf)?;
+            let group = group.unwrap_or(StatsGroup::General);
+            Ok((buf, Cmd::CmdStats { group }))
         }
         "version" => {
             let (buf, _) = crlf(buf)?;
@@ -75,3 +144,96 @@ pub(crate) fn parse_ascii_cmd(buf: &[u8]) -> IResult<&[u8], Cmd> {
         }
     }
 }
+
+fn parse_ascii_store(buf: &[u8], mode: StoreMode) -> IResult<&[u8], Cmd<'_>> {
+    // <command name> <key> <flags> <exptime> <bytes> [noreply]\r\n
+    // data block\r\n
+    let (buf, (key, flag, ttl, len)) = parse_ascii_store_header(buf)?;
+    let (buf, noreply) = parse_ascii_noreply(buf)?;
+    Ok((
+        buf,
+        Cmd::CmdStore {
+            mode,
+            key,
+            flag,
+            ttl,
+            len,
+            noreply,
+        },
+    ))
+}
+
+fn parse_ascii_cas(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    // cas <key> <flags> <exptime> <bytes> <cas unique> [noreply]\r\n
+    // data block\r\n
+    let (buf, (key, flag, ttl, len)) = parse_ascii_store_header(buf)?;
+    let (buf, (_, unique)) = tuple((tag(" "), parse_ascii_u64))(buf)?;
+    let (buf, noreply) = parse_ascii_noreply(buf)?;
+    Ok((
+        buf,
+        Cmd::CmdStore {
+            mode: StoreMode::Cas(unique),
+            key,
+            flag,
+            ttl,
+            len,
+            noreply,
+        },
+    ))
+}
+
+/// Parses ` <key>*\r\n`, one or more space separated keys until the end of the command line.
+fn parse_ascii_keys(buf: &[u8]) -> IResult<&[u8], Vec<&[u8]>> {
+    let (buf, (keys, _)) = tuple((many1(preceded(tag(" "), take_while1(is_key_char))), crlf))(buf)?;
+    Ok((buf, keys))
+}
+
+/// Parses ` <key> <flags> <exptime> <bytes>` shared by all storage commands.
+fn parse_ascii_store_header(buf: &[u8]) -> IResult<&[u8], (&[u8], u32, u32, u32)> {
+    let (buf, (_, key, _, flag, _, ttl, _, len)) = tuple((
+        tag(" "),
+        take_while1(is_key_char),
+        tag(" "),
+        parse_ascii_u32, // flag
+        tag(" "),
+        parse_ascii_u32, // ttl
+        tag(" "),
+        parse_ascii_u32, // len
+    ))(buf)?;
+    Ok((buf, (key, flag, ttl, len)))
+}
+
+fn parse_ascii_counter(buf: &[u8], incr: bool) -> IResult<&[u8], Cmd<'_>> {
+    // incr|decr <key> <value> [noreply]\r\n
+    let (buf, (_, key, _, delta)) = tuple((
+        tag(" "),
+        take_while1(is_key_char),
+        tag(" "),
+        parse_ascii_u64,
+    ))(buf)?;
+    let (buf, noreply) = parse_ascii_noreply(buf)?;
+    let cmd = if incr {
+        Cmd::CmdIncr {
+            key,
+            delta,
+            noreply,
+        }
+    } else {
+        Cmd::CmdDecr {
+            key,
+            delta,
+            noreply,
+        }
+    };
+    Ok((buf, cmd))
+}
+
+/// Parses the optional trailing `noreply` and the end of the command line.
+fn parse_ascii_noreply(buf: &[u8]) -> IResult<&[u8], Option<bool>> {
+    let (buf, (_, noreply, _)) = tuple((
+        opt(tag(" ")),
+        opt(alt((value(true, tag_no_case(b"noreply")),))),
+        crlf,
+    ))(buf)?;
+    Ok((buf, noreply))
+}
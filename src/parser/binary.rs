@@ -0,0 +1,421 @@
+use crate::parser::{Cmd, StoreMode};
+use nom::bytes::complete::take;
+use nom::combinator::{eof, verify};
+use nom::error::{Error, ErrorKind};
+use nom::number::complete::{be_u16, be_u32, be_u64, be_u8};
+use nom::sequence::tuple;
+use nom::IResult;
+
+/// Every binary packet starts with a header of this many bytes.
+pub const HEADER_LEN: usize = 24;
+pub const MAGIC_REQUEST: u8 = 0x80;
+pub const MAGIC_RESPONSE: u8 = 0x81;
+
+/// Incr and decr with this ttl fail on a missing key instead of creating it.
+pub const NO_AUTO_CREATE: u32 = 0xffff_ffff;
+
+/// Opcodes of the binary protocol, the `Q` suffixed ones are the quiet variants.
+pub mod opcode {
+    pub const GET: u8 = 0x00;
+    pub const SET: u8 = 0x01;
+    pub const ADD: u8 = 0x02;
+    pub const REPLACE: u8 = 0x03;
+    pub const DELETE: u8 = 0x04;
+    pub const INCREMENT: u8 = 0x05;
+    pub const DECREMENT: u8 = 0x06;
+    pub const QUIT: u8 = 0x07;
+    pub const FLUSH: u8 = 0x08;
+    pub const GETQ: u8 = 0x09;
+    pub const NOOP: u8 = 0x0a;
+    pub const VERSION: u8 = 0x0b;
+    pub const GETK: u8 = 0x0c;
+    pub const GETKQ: u8 = 0x0d;
+    pub const APPEND: u8 = 0x0e;
+    pub const PREPEND: u8 = 0x0f;
+    pub const SETQ: u8 = 0x11;
+    pub const ADDQ: u8 = 0x12;
+    pub const REPLACEQ: u8 = 0x13;
+    pub const DELETEQ: u8 = 0x14;
+    pub const INCREMENTQ: u8 = 0x15;
+    pub const DECREMENTQ: u8 = 0x16;
+    pub const QUITQ: u8 = 0x17;
+    pub const FLUSHQ: u8 = 0x18;
+    pub const APPENDQ: u8 = 0x19;
+    pub const PREPENDQ: u8 = 0x1a;
+    pub const TOUCH: u8 = 0x1c;
+    pub const GAT: u8 = 0x1d;
+    pub const GATQ: u8 = 0x1e;
+
+    /// Quiet commands only send a response on failure, except quiet gets which only send one
+    /// on a hit.
+    pub fn is_quiet(opcode: u8) -> bool {
+        matches!(
+            opcode,
+            GETQ | GETKQ
+                | SETQ
+                | ADDQ
+                | REPLACEQ
+                | DELETEQ
+                | INCREMENTQ
+                | DECREMENTQ
+                | QUITQ
+                | FLUSHQ
+                | APPENDQ
+                | PREPENDQ
+                | GATQ
+        )
+    }
+
+    /// Whether the command returns a value, so a miss is the failure case.
+    pub fn is_get(opcode: u8) -> bool {
+        matches!(opcode, GET | GETQ | GETK | GETKQ | GAT | GATQ)
+    }
+}
+
+/// Response status codes of the binary protocol.
+pub mod status {
+    pub const NO_ERROR: u16 = 0x0000;
+    pub const KEY_NOT_FOUND: u16 = 0x0001;
+    pub const KEY_EXISTS: u16 = 0x0002;
+    pub const VALUE_TOO_LARGE: u16 = 0x0003;
+    pub const INVALID_ARGUMENTS: u16 = 0x0004;
+    pub const NOT_STORED: u16 = 0x0005;
+    pub const NON_NUMERIC: u16 = 0x0006;
+    pub const UNKNOWN_COMMAND: u16 = 0x0081;
+}
+
+/// The fixed size header in front of every binary request and response.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    pub magic: u8,
+    pub opcode: u8,
+    pub key_len: u16,
+    pub extras_len: u8,
+    pub data_type: u8,
+    /// The vbucket id in requests, the status in responses.
+    pub status: u16,
+    /// Length of extras, key and value together.
+    pub body_len: u32,
+    /// Copied as is from a request into its response.
+    pub opaque: u32,
+    pub cas: u64,
+}
+
+/// A binary request, mapped onto the command shared with the ascii protocol.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BinaryRequest<'a> {
+    /// `None` for noop and quit, which only concern the connection.
+    pub cmd: Option<Cmd<'a>>,
+    /// The data of a storage command.
+    pub value: &'a [u8],
+    /// For incr and decr, the value and ttl to create a missing counter with.
+    pub initial: Option<(u64, u32)>,
+}
+
+/// Parses a request header, the body is then `body_len` bytes long.
+pub(crate) fn parse_binary_header(buf: &[u8]) -> IResult<&[u8], Header> {
+    let (buf, (magic, opcode, key_len, extras_len, data_type, status, body_len, opaque, cas)) =
+        tuple((
+            verify(be_u8, |magic| *magic == MAGIC_REQUEST),
+            be_u8,
+            be_u16,
+            be_u8,
+            be_u8,
+            be_u16,
+            be_u32,
+            be_u32,
+            be_u64,
+        ))(buf)?;
+    Ok((
+        buf,
+        Header {
+            magic,
+            opcode,
+            key_len,
+            extras_len,
+            data_type,
+            status,
+            body_len,
+            opaque,
+            cas,
+        },
+    ))
+}
+
+/// Parses the body of a request into the command it executes.
+///
+/// Fails on unknown opcodes and on bodies that do not have the extras, key and value the opcode
+/// requires.
+pub(crate) fn parse_binary_request<'a>(
+    header: &Header,
+    body: &'a [u8],
+) -> IResult<&'a [u8], BinaryRequest<'a>> {
+    let value_len = (header.body_len as usize)
+        .checked_sub(header.extras_len as usize + header.key_len as usize)
+        .ok_or_else(|| nom::Err::Error(Error::new(body, ErrorKind::LengthValue)))?;
+    let (buf, (extras, key, value)) = tuple((
+        take(header.extras_len),
+        take(header.key_len),
+        take(value_len),
+    ))(body)?;
+    let (buf, _) = eof(buf)?;
+    let has_key = !key.is_empty();
+    let has_value = !value.is_empty();
+    let quiet = Some(opcode::is_quiet(header.opcode));
+
+    // Checks the body against the extras length the opcode requires and whether it needs a key
+    // and allows a value.
+    let expect = |extras_len: usize, key_required: bool, value_allowed: bool| {
+        if extras.len() == extras_len && has_key == key_required && (value_allowed || !has_value) {
+            Ok(())
+        } else {
+            Err(nom::Err::Error(Error::new(body, ErrorKind::Verify)))
+        }
+    };
+
+    let mut request = BinaryRequest {
+        cmd: None,
+        value: &[],
+        initial: None,
+    };
+    match header.opcode {
+        opcode::GET | opcode::GETQ | opcode::GETK | opcode::GETKQ => {
+            expect(0, true, false)?;
+            request.cmd = Some(Cmd::CmdGet { keys: vec![key] });
+        }
+        opcode::SET
+        | opcode::SETQ
+        | opcode::ADD
+        | opcode::ADDQ
+        | opcode::REPLACE
+        | opcode::REPLACEQ => {
+            expect(8, true, true)?;
+            let (_, (flag, ttl)) = tuple((be_u32, be_u32))(extras)?;
+            let mode = match header.opcode {
+                opcode::ADD | opcode::ADDQ => StoreMode::Add,
+                // a set or replace with a cas only succeeds if the item is unchanged since it was read
+                _ if header.cas != 0 => StoreMode::Cas(header.cas),
+                opcode::SET | opcode::SETQ => StoreMode::Set,
+                _ => StoreMode::Replace,
+            };
+            request.cmd = Some(Cmd::CmdStore {
+                mode,
+                key,
+                flag,
+                ttl,
+                len: value.len() as u32,
+                noreply: quiet,
+            });
+            request.value = value;
+        }
+        opcode::APPEND | opcode::APPENDQ | opcode::PREPEND | opcode::PREPENDQ => {
+            expect(0, true, true)?;
+            let mode = match header.opcode {
+                opcode::APPEND | opcode::APPENDQ => StoreMode::Append,
+                _ => StoreMode::Prepend,
+            };
+            request.cmd = Some(Cmd::CmdStore {
+                mode,
+                key,
+                flag: 0,
+                ttl: 0,
+                len: value.len() as u32,
+                noreply: quiet,
+            });
+            request.value = value;
+        }
+        opcode::DELETE | opcode::DELETEQ => {
+            expect(0, true, false)?;
+            request.cmd = Some(Cmd::CmdDelete {
+                key,
+                // a delete with a cas only succeeds if the item is unchanged since it was read
+                cas: (header.cas != 0).then_some(header.cas),
+                noreply: quiet,
+            });
+        }
+        opcode::INCREMENT | opcode::INCREMENTQ | opcode::DECREMENT | opcode::DECREMENTQ => {
+            expect(20, true, false)?;
+            let (_, (delta, initial, ttl)) = tuple((be_u64, be_u64, be_u32))(extras)?;
+            request.cmd = Some(match header.opcode {
+                opcode::INCREMENT | opcode::INCREMENTQ => Cmd::CmdIncr {
+                    key,
+                    delta,
+                    noreply: quiet,
+                },
+                _ => Cmd::CmdDecr {
+                    key,
+                    delta,
+                    noreply: quiet,
+                },
+            });
+            request.initial = Some((initial, ttl));
+        }
+        opcode::TOUCH => {
+            expect(4, true, false)?;
+            let (_, ttl) = be_u32(extras)?;
+            request.cmd = Some(Cmd::CmdTouch {
+                key,
+                ttl,
+                noreply: quiet,
+            });
+        }
+        opcode::GAT | opcode::GATQ => {
+            expect(4, true, false)?;
+            let (_, ttl) = be_u32(extras)?;
+            request.cmd = Some(Cmd::CmdGat {
+                ttl,
+                keys: vec![key],
+            });
+        }
+        opcode::FLUSH | opcode::FLUSHQ => {
+            // the delay is optional
+            let delay = if extras.is_empty() {
+                expect(0, false, false)?;
+                None
+            } else {
+                expect(4, false, false)?;
+                Some(be_u32(extras)?.1)
+            };
+            request.cmd = Some(Cmd::CmdFlushAll {
+                delay,
+                noreply: quiet,
+            });
+        }
+        opcode::VERSION => {
+            expect(0, false, false)?;
+            request.cmd = Some(Cmd::CmdVersion);
+        }
+        opcode::NOOP | opcode::QUIT | opcode::QUITQ => expect(0, false, false)?,
+        _ => return Err(nom::Err::Error(Error::new(body, ErrorKind::Tag))),
+    }
+    Ok((buf, request))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(opcode: u8, extras_len: u8, key_len: u16, body_len: u32) -> Header {
+        Header {
+            magic: MAGIC_REQUEST,
+            opcode,
+            key_len,
+            extras_len,
+            data_type: 0,
+            status: 0,
+            body_len,
+            opaque: 0,
+            cas: 0,
+        }
+    }
+
+    fn parse<'a>(header: &Header, body: &'a [u8]) -> BinaryRequest<'a> {
+        parse_binary_request(header, body).unwrap().1
+    }
+
+    fn error_kind(header: &Header, body: &[u8]) -> ErrorKind {
+        match parse_binary_request(header, body) {
+            Err(nom::Err::Error(e)) => e.code,
+            result => panic!("not an error: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn parses_a_header() {
+        let mut packet = vec![0x80, opcode::SET, 0, 3, 8, 0, 0, 0, 0, 0, 0, 16];
+        packet.extend_from_slice(&7u32.to_be_bytes());
+        packet.extend_from_slice(&9u64.to_be_bytes());
+        packet.extend_from_slice(b"body");
+        let (rest, header) = parse_binary_header(&packet).unwrap();
+        assert_eq!(rest, b"body");
+        assert_eq!(
+            header,
+            Header {
+                opaque: 7,
+                cas: 9,
+                ..self::header(opcode::SET, 8, 3, 16)
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_a_header_with_bad_magic() {
+        let mut packet = vec![0; HEADER_LEN];
+        packet[0] = MAGIC_RESPONSE;
+        assert!(parse_binary_header(&packet).is_err());
+        assert!(parse_binary_header(&[MAGIC_REQUEST; HEADER_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn decodes_the_extras_of_a_store() {
+        let mut body = vec![0, 0, 0, 5, 0, 0, 0, 60];
+        body.extend_from_slice(b"keyvalue");
+        let request = parse(&header(opcode::SETQ, 8, 3, 16), &body);
+        assert_eq!(request.value, b"value");
+        assert_eq!(
+            request.cmd,
+            Some(Cmd::CmdStore {
+                mode: StoreMode::Set,
+                key: b"key",
+                flag: 5,
+                ttl: 60,
+                len: 5,
+                noreply: Some(true),
+            })
+        );
+    }
+
+    #[test]
+    fn decodes_the_extras_of_a_counter() {
+        let mut body = Vec::new();
+        body.extend_from_slice(&2u64.to_be_bytes());
+        body.extend_from_slice(&10u64.to_be_bytes());
+        body.extend_from_slice(&NO_AUTO_CREATE.to_be_bytes());
+        body.extend_from_slice(b"key");
+        let request = parse(&header(opcode::DECREMENT, 20, 3, 23), &body);
+        assert_eq!(request.initial, Some((10, NO_AUTO_CREATE)));
+        assert_eq!(
+            request.cmd,
+            Some(Cmd::CmdDecr {
+                key: b"key",
+                delta: 2,
+                noreply: Some(false),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_bodies_which_do_not_fit_the_opcode() {
+        // extras and key longer than the body
+        assert_eq!(
+            error_kind(&header(opcode::GET, 0, 4, 3), b"key"),
+            ErrorKind::LengthValue
+        );
+        // the body holds more than the header announces
+        assert_eq!(
+            error_kind(&header(opcode::GET, 0, 3, 3), b"keyx"),
+            ErrorKind::Eof
+        );
+        // a get has no extras and no value
+        assert_eq!(
+            error_kind(&header(opcode::GET, 0, 3, 4), b"keyx"),
+            ErrorKind::Verify
+        );
+        // a set needs its extras
+        assert_eq!(
+            error_kind(&header(opcode::SET, 0, 3, 4), b"keyx"),
+            ErrorKind::Verify
+        );
+        assert_eq!(error_kind(&header(0x55, 0, 0, 0), b""), ErrorKind::Tag);
+    }
+
+    #[test]
+    fn tells_quiet_commands_apart() {
+        assert!(opcode::is_quiet(opcode::GETKQ));
+        assert!(opcode::is_quiet(opcode::SETQ));
+        assert!(!opcode::is_quiet(opcode::GETK));
+        assert!(opcode::is_get(opcode::GETQ));
+        assert!(opcode::is_get(opcode::GAT));
+        assert!(!opcode::is_get(opcode::SETQ));
+    }
+}
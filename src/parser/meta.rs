@@ -0,0 +1,225 @@
+use crate::parser::Cmd;
+use btoi::btou;
+use nom::{
+    branch::alt,
+    bytes::streaming::{tag, take_while1, take_while_m_n},
+    character::{is_digit, streaming::crlf},
+    combinator::{map, map_res, value},
+    multi::many0,
+    sequence::{preceded, tuple},
+    IResult,
+};
+use std::str::FromStr;
+
+/// A flag of a meta command, a single character optionally followed by a token, e.g. `T30`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetaFlag {
+    pub flag: u8,
+    pub token: Vec<u8>,
+}
+
+/// The flags of a meta command, kept in the order the client sent them as return flags are
+/// written back in the same order.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MetaFlags(pub Vec<MetaFlag>);
+
+impl MetaFlags {
+    pub fn has(&self, flag: u8) -> bool {
+        self.0.iter().any(|f| f.flag == flag)
+    }
+
+    pub fn token(&self, flag: u8) -> Option<&[u8]> {
+        self.0
+            .iter()
+            .find(|f| f.flag == flag)
+            .map(|f| f.token.as_slice())
+    }
+
+    /// Parses the token of a flag as a number, `Err` if the token is not a valid number.
+    pub fn number<T: FromStr>(&self, flag: u8) -> Result<Option<T>, ()> {
+        match self.token(flag) {
+            Some(token) => std::str::from_utf8(token)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .map(Some)
+                .ok_or(()),
+            None => Ok(None),
+        }
+    }
+}
+
+fn is_token_char(chr: u8) -> bool {
+    chr > 32 && chr < 127
+}
+
+fn parse_meta_flags(buf: &[u8]) -> IResult<&[u8], MetaFlags> {
+    let (buf, (flags, _)) = tuple((
+        many0(map(
+            preceded(tag(" "), take_while1(is_token_char)),
+            |token: &[u8]| MetaFlag {
+                flag: token[0],
+                token: token[1..].to_vec(),
+            },
+        )),
+        crlf,
+    ))(buf)?;
+    Ok((buf, MetaFlags(flags)))
+}
+
+fn parse_meta_key(buf: &[u8]) -> IResult<&[u8], &[u8]> {
+    preceded(tag(" "), take_while1(is_token_char))(buf)
+}
+
+/// Parses a meta command, `mg`, `ms`, `md`, `ma`, `mn` or `me`.
+pub(crate) fn parse_meta_cmd(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    let (buf, c) = alt((
+        value("mg", tag(b"mg")),
+        value("ms", tag(b"ms")),
+        value("md", tag(b"md")),
+        value("ma", tag(b"ma")),
+        value("mn", tag(b"mn")),
+        value("me", tag(b"me")),
+    ))(buf)?;
+
+    match c {
+        "mg" => {
+            // mg <key> <flags>*\r\n
+            let (buf, (key, flags)) = tuple((parse_meta_key, parse_meta_flags))(buf)?;
+            Ok((buf, Cmd::CmdMetaGet { key, flags }))
+        }
+        "ms" => {
+            // ms <key> <datalen> <flags>*\r\n
+            // data block\r\n
+            let (buf, (key, len, flags)) = tuple((
+                parse_meta_key,
+                preceded(
+                    tag(" "),
+                    map_res(take_while_m_n(1, 10, is_digit), btou::<u32>),
+                ),
+                parse_meta_flags,
+            ))(buf)?;
+            Ok((buf, Cmd::CmdMetaSet { key, len, flags }))
+        }
+        "md" => {
+            // md <key> <flags>*\r\n
+            let (buf, (key, flags)) = tuple((parse_meta_key, parse_meta_flags))(buf)?;
+            Ok((buf, Cmd::CmdMetaDelete { key, flags }))
+        }
+        "ma" => {
+            // ma <key> <flags>*\r\n
+            let (buf, (key, flags)) = tuple((parse_meta_key, parse_meta_flags))(buf)?;
+            Ok((buf, Cmd::CmdMetaArithmetic { key, flags }))
+        }
+        "mn" => {
+            let (buf, _) = crlf(buf)?;
+            Ok((buf, Cmd::CmdMetaNoop))
+        }
+        "me" => {
+            // me <key> [b]\r\n
+            let (buf, (key, flags)) = tuple((parse_meta_key, parse_meta_flags))(buf)?;
+            Ok((buf, Cmd::CmdMetaDebug { key, flags }))
+        }
+        _ => {
+            panic!("not possible")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flag(flag: u8, token: &[u8]) -> MetaFlag {
+        MetaFlag {
+            flag,
+            token: token.to_vec(),
+        }
+    }
+
+    fn flags(cmd: &[u8]) -> MetaFlags {
+        match parse_meta_cmd(cmd).unwrap() {
+            (_, Cmd::CmdMetaGet { flags, .. }) => flags,
+            (_, cmd) => panic!("not mg: {:?}", cmd),
+        }
+    }
+
+    #[test]
+    fn parses_flags_in_order() {
+        let (rest, cmd) = parse_meta_cmd(b"mg key v T30 Oab1 k\r\nmn\r\n").unwrap();
+        assert_eq!(rest, b"mn\r\n");
+        assert_eq!(
+            cmd,
+            Cmd::CmdMetaGet {
+                key: b"key",
+                flags: MetaFlags(vec![
+                    flag(b'v', b""),
+                    flag(b'T', b"30"),
+                    flag(b'O', b"ab1"),
+                    flag(b'k', b""),
+                ]),
+            }
+        );
+    }
+
+    #[test]
+    fn parses_each_command() {
+        let (_, cmd) = parse_meta_cmd(b"ms key 5 T-1 F3\r\nhello\r\n").unwrap();
+        assert_eq!(
+            cmd,
+            Cmd::CmdMetaSet {
+                key: b"key",
+                len: 5,
+                flags: MetaFlags(vec![flag(b'T', b"-1"), flag(b'F', b"3")]),
+            }
+        );
+        assert_eq!(
+            parse_meta_cmd(b"md key q\r\n").unwrap().1,
+            Cmd::CmdMetaDelete {
+                key: b"key",
+                flags: MetaFlags(vec![flag(b'q', b"")]),
+            }
+        );
+        assert_eq!(
+            parse_meta_cmd(b"ma key\r\n").unwrap().1,
+            Cmd::CmdMetaArithmetic {
+                key: b"key",
+                flags: MetaFlags::default(),
+            }
+        );
+        assert_eq!(
+            parse_meta_cmd(b"me key\r\n").unwrap().1,
+            Cmd::CmdMetaDebug {
+                key: b"key",
+                flags: MetaFlags::default(),
+            }
+        );
+        assert_eq!(parse_meta_cmd(b"mn\r\n").unwrap().1, Cmd::CmdMetaNoop);
+    }
+
+    #[test]
+    fn reads_tokens() {
+        let flags = flags(b"mg key T30 N-1 Rx v\r\n");
+        assert!(flags.has(b'v'));
+        assert!(!flags.has(b'q'));
+        assert_eq!(flags.token(b'v'), Some(&b""[..]));
+        assert_eq!(flags.token(b'q'), None);
+        assert_eq!(flags.number::<u32>(b'T'), Ok(Some(30)));
+        assert_eq!(flags.number::<i64>(b'N'), Ok(Some(-1)));
+        assert_eq!(flags.number::<u32>(b'N'), Err(()));
+        assert_eq!(flags.number::<u32>(b'R'), Err(()));
+        assert_eq!(flags.number::<u32>(b'q'), Ok(None));
+    }
+
+    #[test]
+    fn rejects_bad_command_lines() {
+        // the set needs a length
+        assert!(parse_meta_cmd(b"ms key T30\r\n").is_err());
+        // flags are separated by a single space
+        assert!(parse_meta_cmd(b"mg key  v\r\n").is_err());
+        assert!(parse_meta_cmd(b"mx key\r\n").is_err());
+        assert!(matches!(
+            parse_meta_cmd(b"mg key v"),
+            Err(nom::Err::Incomplete(_))
+        ));
+    }
+}
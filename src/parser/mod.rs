@@ -1,11 +1,65 @@
 pub mod ascii;
+pub mod binary;
+pub mod meta;
 
-/// A set command from client.
+use meta::MetaFlags;
+
+/// How a storage command stores its data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StoreMode {
+    /// Store the data unconditionally.
+    Set,
+    /// Store the data only if the key does not exist yet.
+    Add,
+    /// Store the data only if the key already exists.
+    Replace,
+    /// Add the data after the existing value of the key.
+    Append,
+    /// Add the data before the existing value of the key.
+    Prepend,
+    /// Store the data only if the key was not updated since the client fetched the given cas
+    /// unique.
+    Cas(u64),
+}
+
+impl StoreMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StoreMode::Set => "set",
+            StoreMode::Add => "add",
+            StoreMode::Replace => "replace",
+            StoreMode::Append => "append",
+            StoreMode::Prepend => "prepend",
+            StoreMode::Cas(_) => "cas",
+        }
+    }
+}
+
+/// Which statistics a stats command asks for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StatsGroup {
+    /// `stats`, the general purpose statistics.
+    General,
+    /// `stats settings`
+    Settings,
+    /// `stats items`
+    Items,
+    /// `stats sizes`
+    Sizes,
+    /// `stats reset`, clears the statistics counters.
+    Reset,
+}
+
+/// A command from client, its keys are borrowed from the request it was parsed from.
+#[allow(clippy::enum_variant_names)]
 #[derive(Clone, Debug, PartialEq)]
-pub enum Cmd {
-    CmdSet {
+pub enum Cmd<'a> {
+    /// A storage command (set, add, replace, append, prepend or cas) from client.
+    CmdStore {
+        /// How the data is stored.
+        mode: StoreMode,
         /// The key.
-        key: Vec<u8>,
+        key: &'a [u8],
         /// Flag for this key.
         ///
         /// Defaults to 0.
@@ -22,9 +76,172 @@ pub enum Cmd {
 
     /// A get command from client.
     CmdGet {
+        /// The keys.
+        keys: Vec<&'a [u8]>,
+    },
+
+    /// A gets command from client, like get but also returns the cas unique.
+    CmdGets {
+        /// The keys.
+        keys: Vec<&'a [u8]>,
+    },
+
+    /// A gat command from client, like get but also updates the ttl of the key.
+    CmdGat {
+        /// New ttl for the keys.
+        ttl: u32,
+        /// The keys.
+        keys: Vec<&'a [u8]>,
+    },
+
+    /// A gats command from client, like gat but also returns the cas unique.
+    CmdGats {
+        /// New ttl for the keys.
+        ttl: u32,
+        /// The keys.
+        keys: Vec<&'a [u8]>,
+    },
+
+    /// A touch command from client.
+    CmdTouch {
+        /// The key.
+        key: &'a [u8],
+        /// New ttl for the key.
+        ttl: u32,
+        /// noreply
+        noreply: Option<bool>,
+    },
+
+    /// A delete command from client.
+    CmdDelete {
+        /// The key.
+        key: &'a [u8],
+        /// Only deletes if the cas unique of the item equals this one, binary protocol only.
+        cas: Option<u64>,
+        /// noreply
+        noreply: Option<bool>,
+    },
+
+    /// An incr command from client.
+    CmdIncr {
+        /// The key.
+        key: &'a [u8],
+        /// Amount to add to the counter.
+        delta: u64,
+        /// noreply
+        noreply: Option<bool>,
+    },
+
+    /// A decr command from client.
+    CmdDecr {
         /// The key.
-        key: Vec<u8>,
+        key: &'a [u8],
+        /// Amount to subtract from the counter.
+        delta: u64,
+        /// noreply
+        noreply: Option<bool>,
     },
 
+    /// A flush_all command from client.
+    CmdFlushAll {
+        /// Seconds to wait before invalidating the items, flush right away if not given.
+        delay: Option<u32>,
+        /// noreply
+        noreply: Option<bool>,
+    },
+
+    /// A stats command from client.
+    CmdStats {
+        /// Which statistics to return.
+        group: StatsGroup,
+    },
+
+    /// A meta get (mg) command from client.
+    CmdMetaGet {
+        /// The key, base64 encoded if the b flag is given.
+        key: &'a [u8],
+        flags: MetaFlags,
+    },
+
+    /// A meta set (ms) command from client.
+    CmdMetaSet {
+        /// The key, base64 encoded if the b flag is given.
+        key: &'a [u8],
+        /// Length of data
+        len: u32,
+        flags: MetaFlags,
+    },
+
+    /// A meta delete (md) command from client.
+    CmdMetaDelete {
+        /// The key, base64 encoded if the b flag is given.
+        key: &'a [u8],
+        flags: MetaFlags,
+    },
+
+    /// A meta arithmetic (ma) command from client.
+    CmdMetaArithmetic {
+        /// The key, base64 encoded if the b flag is given.
+        key: &'a [u8],
+        flags: MetaFlags,
+    },
+
+    /// A meta debug (me) command from client.
+    CmdMetaDebug {
+        /// The key, base64 encoded if the b flag is given.
+        key: &'a [u8],
+        flags: MetaFlags,
+    },
+
+    /// A meta no-op (mn) command from client.
+    CmdMetaNoop,
+
     CmdVersion,
 }
+
+impl Cmd<'_> {
+    /// The command name, used as the metrics label.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Cmd::CmdStore { mode, .. } => mode.name(),
+            Cmd::CmdGet { .. } => "get",
+            Cmd::CmdGets { .. } => "gets",
+            Cmd::CmdGat { .. } => "gat",
+            Cmd::CmdGats { .. } => "gats",
+            Cmd::CmdTouch { .. } => "touch",
+            Cmd::CmdDelete { .. } => "delete",
+            Cmd::CmdIncr { .. } => "incr",
+            Cmd::CmdDecr { .. } => "decr",
+            Cmd::CmdFlushAll { .. } => "flush_all",
+            Cmd::CmdStats { .. } => "stats",
+            Cmd::CmdMetaGet { .. } => "mg",
+            Cmd::CmdMetaSet { .. } => "ms",
+            Cmd::CmdMetaDelete { .. } => "md",
+            Cmd::CmdMetaArithmetic { .. } => "ma",
+            Cmd::CmdMetaDebug { .. } => "me",
+            Cmd::CmdMetaNoop => "mn",
+            Cmd::CmdVersion => "version",
+        }
+    }
+
+    /// Length of the data block following the command, storage commands only.
+    pub fn data_len(&self) -> Option<u32> {
+        match self {
+            Cmd::CmdStore { len, .. } | Cmd::CmdMetaSet { len, .. } => Some(*len),
+            _ => None,
+        }
+    }
+
+    /// Whether the client asked not to get a reply.
+    pub fn noreply(&self) -> bool {
+        match self {
+            Cmd::CmdStore { noreply, .. }
+            | Cmd::CmdTouch { noreply, .. }
+            | Cmd::CmdDelete { noreply, .. }
+            | Cmd::CmdIncr { noreply, .. }
+            | Cmd::CmdDecr { noreply, .. }
+            | Cmd::CmdFlushAll { noreply, .. } => noreply.unwrap_or(false),
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! Persists the cache to a snapshot file, so a restart does not hammer the backends with the
+//! misses of an empty cache.
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use kv_cache::Cache;
+use log::{info, warn};
+
+#[derive(Clone)]
+pub struct Snapshots {
+    cache: Cache<Vec<u8>, Bytes>,
+    path: PathBuf,
+    /// Held while writing, the periodic and the shutdown snapshot share the temporary file.
+    writing: Arc<Mutex<()>>,
+}
+
+impl Snapshots {
+    pub fn new(cache: Cache<Vec<u8>, Bytes>, path: PathBuf) -> Self {
+        Snapshots {
+            cache,
+            path,
+            writing: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Restores the cache from the snapshot file. A missing or unreadable snapshot leaves the
+    /// cache empty, it is only logged.
+    pub fn load(&self) {
+        let start = Instant::now();
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                info!("no snapshot at {}, starting empty", self.path.display());
+                return;
+            }
+            Err(e) => {
+                warn!("can not open snapshot {}: {}", self.path.display(), e);
+                return;
+            }
+        };
+        match self.cache.read_snapshot(BufReader::new(file)) {
+            Ok(items) => info!(
+                "restored {} items from {} in {:?}",
+                items,
+                self.path.display(),
+                start.elapsed()
+            ),
+            Err(e) => warn!("ignoring snapshot {}: {}", self.path.display(), e),
+        }
+    }
+
+    /// Writes a snapshot next to the current one and moves it in place once it is complete, so a
+    /// crash while writing leaves the previous snapshot intact.
+    pub fn write(&self) -> io::Result<u64> {
+        let _writing = self.writing.lock().unwrap();
+        let start = Instant::now();
+        let tmp_path = self.path.with_extension("tmp");
+        let file = File::create(&tmp_path)?;
+        let mut writer = BufWriter::new(file);
+        let items = self.cache.write_snapshot(&mut writer)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        info!(
+            "wrote {} items to {} in {:?}",
+            items,
+            self.path.display(),
+            start.elapsed()
+        );
+        Ok(items)
+    }
+
+    /// Writes a snapshot every interval, off the runtime threads.
+    pub async fn write_periodically(self, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            let snapshots = self.clone();
+            match tokio::task::spawn_blocking(move || snapshots.write()).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => warn!("can not write snapshot {}: {}", self.path.display(), e),
+                Err(e) => warn!("snapshot writer failed: {}", e),
+            }
+        }
+    }
+}
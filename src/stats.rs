@@ -0,0 +1,101 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use kv_cache::Cache;
+
+/// Server level counters reported by the `stats` command, the cache level ones come from
+/// [`Cache::stats`].
+pub struct ServerStats {
+    started: SystemTime,
+    pub curr_connections: AtomicU64,
+    pub total_connections: AtomicU64,
+    pub cmd_get: AtomicU64,
+    pub cmd_set: AtomicU64,
+    pub cmd_touch: AtomicU64,
+    pub cmd_flush: AtomicU64,
+}
+
+impl ServerStats {
+    pub fn new() -> Self {
+        ServerStats {
+            started: SystemTime::now(),
+            curr_connections: AtomicU64::new(0),
+            total_connections: AtomicU64::new(0),
+            cmd_get: AtomicU64::new(0),
+            cmd_set: AtomicU64::new(0),
+            cmd_touch: AtomicU64::new(0),
+            cmd_flush: AtomicU64::new(0),
+        }
+    }
+
+    pub fn incr(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Resets the counters like `stats reset`, gauges such as curr_connections are kept.
+    pub fn reset(&self) {
+        self.total_connections.store(0, Ordering::Relaxed);
+        self.cmd_get.store(0, Ordering::Relaxed);
+        self.cmd_set.store(0, Ordering::Relaxed);
+        self.cmd_touch.store(0, Ordering::Relaxed);
+        self.cmd_flush.store(0, Ordering::Relaxed);
+    }
+
+    /// Lines of the general `stats` command.
+    pub fn general(&self, cache: &Cache<Vec<u8>, Bytes>, version: &str) -> Vec<String> {
+        let now = SystemTime::now();
+        let uptime = now.duration_since(self.started).unwrap_or_default();
+        let time = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let cache_stats = cache.stats();
+        vec![
+            stat("pid", std::process::id()),
+            stat("uptime", uptime.as_secs()),
+            stat("time", time.as_secs()),
+            stat("version", version),
+            stat("curr_connections", load(&self.curr_connections)),
+            stat("total_connections", load(&self.total_connections)),
+            stat("cmd_get", load(&self.cmd_get)),
+            stat("cmd_set", load(&self.cmd_set)),
+            stat("cmd_flush", load(&self.cmd_flush)),
+            stat("cmd_touch", load(&self.cmd_touch)),
+            stat("get_hits", cache_stats.hits),
+            stat("get_misses", cache_stats.misses),
+            stat("curr_items", cache_stats.items),
+            stat("total_items", cache_stats.inserts + cache_stats.overwrites),
+            stat("bytes", cache_stats.bytes),
+            stat("limit_maxbytes", cache.max_bytes().unwrap_or(0)),
+            stat("evictions", cache_stats.evictions),
+            stat("expired_unfetched", cache_stats.expired_unfetched),
+            stat("crawler_reclaimed", cache_stats.reclaimed),
+        ]
+    }
+}
+
+/// Lines of the `stats items` command, everything is reported as a single slab class as items
+/// are not stored in slabs.
+pub fn items(cache: &Cache<Vec<u8>, Bytes>) -> Vec<String> {
+    let cache_stats = cache.stats();
+    vec![
+        stat("items:1:number", cache_stats.items),
+        stat("items:1:evicted", cache_stats.evictions),
+        stat("items:1:expired_unfetched", cache_stats.expired_unfetched),
+    ]
+}
+
+/// Lines of the `stats sizes` command, item counts per 32 bytes size bucket.
+pub fn sizes(cache: &Cache<Vec<u8>, Bytes>) -> Vec<String> {
+    cache
+        .item_sizes(32)
+        .into_iter()
+        .map(|(size, count)| stat(&size.to_string(), count))
+        .collect()
+}
+
+pub fn stat<T: std::fmt::Display>(name: &str, value: T) -> String {
+    format!("STAT {} {}", name, value)
+}
+
+fn load(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}